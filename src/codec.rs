@@ -0,0 +1,86 @@
+use snafu::ResultExt;
+
+use crate::{
+    config::models::PayloadCodec,
+    get_settings,
+    mqtt_client::client::MqttMessageProperties,
+    topics::MessageClass,
+    ApplicationError,
+};
+
+/// Encodes a JSON status or event payload with the configured codec, and sets the matching content type. Other payloads,
+/// like the discovery messages Home Assistant has to read and plain text or templated payloads, are left as they are.
+pub fn encode_payload(class: MessageClass, payload: String, properties: &mut MqttMessageProperties) -> Result<Vec<u8>, ApplicationError> {
+    let codec = get_settings().mqtt.codec;
+    let is_json = properties.content_type.as_deref() == Some(PayloadCodec::Json.content_type());
+    if !matches!(class, MessageClass::Status | MessageClass::Event) || !is_json {
+        return Ok(payload.into_bytes());
+    }
+
+    let encoded = encode_json(codec, payload)?;
+    properties.content_type = Some(codec.content_type().to_owned());
+    Ok(encoded)
+}
+
+fn encode_json(codec: PayloadCodec, payload: String) -> Result<Vec<u8>, ApplicationError> {
+    Ok(match codec {
+        PayloadCodec::Json => payload.into_bytes(),
+        PayloadCodec::Cbor => {
+            let value = parse_json(&payload)?;
+            let mut encoded = Vec::new();
+            ciborium::into_writer(&value, &mut encoded).with_whatever_context(|_| "Could not encode payload as CBOR")?;
+            encoded
+        },
+        PayloadCodec::MessagePack => rmp_serde::to_vec_named(&parse_json(&payload)?).with_whatever_context(|_| "Could not encode payload as MessagePack")?,
+    })
+}
+
+/// Payloads are deduplicated and buffered as JSON, so they're only transcoded right before publishing.
+fn parse_json(payload: &str) -> Result<serde_json::Value, ApplicationError> {
+    serde_json::from_str(payload).with_whatever_context(|_| "Could not parse JSON payload")
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn build_payload() -> serde_json::Value {
+        json!({
+            "queue_name": "dnp_left",
+            "state": "Idle",
+            "job_count": 0,
+            "is_accepting_jobs": true,
+            "state_message": null,
+            "markers": [{ "name": "Ribbon", "level": 42 }, { "name": "Paper", "level": -1 }],
+            "ratio": 0.5,
+        })
+    }
+
+    #[test]
+    fn round_trips_cbor() {
+        let encoded = encode_json(PayloadCodec::Cbor, build_payload().to_string()).unwrap();
+        let decoded: serde_json::Value = ciborium::from_reader(encoded.as_slice()).unwrap();
+        assert_eq!(decoded, build_payload());
+    }
+
+    #[test]
+    fn round_trips_message_pack() {
+        let encoded = encode_json(PayloadCodec::MessagePack, build_payload().to_string()).unwrap();
+        let decoded: serde_json::Value = rmp_serde::from_slice(&encoded).unwrap();
+        assert_eq!(decoded, build_payload());
+    }
+
+    #[test]
+    fn leaves_json_as_is() {
+        let payload = build_payload().to_string();
+        assert_eq!(encode_json(PayloadCodec::Json, payload.clone()).unwrap(), payload.into_bytes());
+    }
+
+    #[test]
+    fn rejects_invalid_json() {
+        assert!(encode_json(PayloadCodec::Cbor, "{".to_owned()).is_err());
+        assert!(encode_json(PayloadCodec::MessagePack, "{".to_owned()).is_err());
+    }
+}
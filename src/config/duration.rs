@@ -0,0 +1,75 @@
+use std::{fmt::Formatter, time::Duration};
+
+use serde::{de::{self, Visitor}, Deserialize};
+
+/// A duration which is read from a humantime string, e.g. `90s` or `5m`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HumanDuration(pub Duration);
+
+impl<'de> Deserialize<'de> for HumanDuration {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: serde::Deserializer<'de> {
+        struct HumanDurationVisitor;
+
+        impl<'de> Visitor<'de> for HumanDurationVisitor {
+            type Value = HumanDuration;
+
+            fn expecting(&self, formatter: &mut Formatter) -> Result<(), std::fmt::Error> {
+                formatter.write_str("a duration string")
+            }
+
+            fn visit_str<E>(self, value: &str) -> Result<HumanDuration, E>
+            where
+                E: de::Error,
+            {
+                humantime::parse_duration(value)
+                    .map(HumanDuration)
+                    .map_err(|_| E::custom(format!("Invalid duration string: '{}'", value)))
+            }
+        }
+
+        deserializer.deserialize_str(HumanDurationVisitor)
+    }
+}
+
+/// Reads a duration which can be turned off with `off` or an empty value, for settings which are on by default.
+pub fn deserialize_optional<'de, D>(deserializer: D) -> Result<Option<HumanDuration>, D::Error> where D: serde::Deserializer<'de> {
+    match Option::<String>::deserialize(deserializer)?.as_deref().map(str::trim) {
+        None | Some("") | Some("off") => Ok(None),
+        Some(value) => humantime::parse_duration(value)
+            .map(|duration| Some(HumanDuration(duration)))
+            .map_err(|_| de::Error::custom(format!("Invalid duration string: '{}'", value))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Deserialize)]
+    struct Settings {
+        #[serde(default, deserialize_with = "deserialize_optional")]
+        duration: Option<HumanDuration>,
+    }
+
+    fn parse(json: &str) -> Result<Option<HumanDuration>, serde_json::Error> {
+        serde_json::from_str::<Settings>(json).map(|settings| settings.duration)
+    }
+
+    #[test]
+    fn reads_optional_duration() {
+        assert_eq!(parse(r#"{"duration": "5m"}"#).unwrap(), Some(HumanDuration(Duration::from_secs(300))));
+    }
+
+    #[test]
+    fn turns_off_optional_duration() {
+        assert_eq!(parse(r#"{"duration": "off"}"#).unwrap(), None);
+        assert_eq!(parse(r#"{"duration": ""}"#).unwrap(), None);
+        assert_eq!(parse(r#"{"duration": null}"#).unwrap(), None);
+        assert_eq!(parse("{}").unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_invalid_optional_duration() {
+        assert!(parse(r#"{"duration": "soon"}"#).is_err());
+    }
+}
@@ -1,9 +1,16 @@
-use config::{Config, Environment};
+use std::env;
+
+use config::{Config, Environment, File};
 
 use super::models::Settings;
 
 pub fn load_config() -> Settings {
+    // Settings which don't fit in ENV vars (like the provisioned queues) can be put in a config file.
+    // Any format supported by the config crate works, e.g. `cups2mqtt.toml` or `cups2mqtt.yaml`.
+    let config_file = env::var("C2M_CONFIGFILE").unwrap_or("cups2mqtt".to_owned());
+
     let config = Config::builder()
+        .add_source(File::with_name(&config_file).required(false))
         .add_source(Environment::default()
             .prefix("C2M")
             .separator("_")
@@ -16,18 +23,58 @@ pub fn load_config() -> Settings {
             .set_default("mqtt.host", "localhost").unwrap()
             .set_default("mqtt.port", "1883").unwrap()
             .set_default("mqtt.secure", "false").unwrap()
+            .set_default("mqtt.transport", "tcp").unwrap()
+            .set_default("mqtt.websocketpath", "/mqtt").unwrap()
             .set_default("mqtt.ignoretlserrors", "false").unwrap()
             .set_default("mqtt.username", "").unwrap()
             .set_default("mqtt.password", "").unwrap()
             .set_default("mqtt.clientid", "cups2mqtt").unwrap()
             .set_default("mqtt.roottopic", "cups2mqtt").unwrap()
+            .set_default("mqtt.protocolversion", "v311").unwrap()
+            .set_default("mqtt.removedqueuegraceperiod", "5m").unwrap()
+            .set_default("mqtt.flattopics", "false").unwrap()
+            .set_default("mqtt.publishpolledat", "false").unwrap()
+            .set_default("mqtt.codec", "json").unwrap()
+            .set_default("mqtt.topics.serverstatus", "{root_topic}/cups_server").unwrap()
+            .set_default("mqtt.topics.queuestatus", "{root_topic}/{queue}").unwrap()
+            .set_default("mqtt.topics.stuckjobs", "{root_topic}/{queue}/stuck_jobs").unwrap()
+            .set_default("mqtt.topics.failover", "{root_topic}/{queue}/failover").unwrap()
+            .set_default("mqtt.topics.failoverevents", "{root_topic}/{queue}/failover/events").unwrap()
+            .set_default("mqtt.topics.provisioning", "{root_topic}/cups_server/provisioning").unwrap()
+            .set_default("mqtt.topics.audit", "{root_topic}/cups_server/audit").unwrap()
+            .set_default("mqtt.topics.job", "{root_topic}/{queue}/jobs/{job_id}").unwrap()
+            .set_default("mqtt.topics.jobevents", "{root_topic}/{queue}/jobs/events").unwrap()
+            .set_default("mqtt.topics.devices", "{root_topic}/cups_server/devices").unwrap()
+            .set_default("mqtt.topics.scandevicescommand", "{root_topic}/cups_server/devices/scan").unwrap()
+            .set_default("mqtt.topics.printcommand", "{root_topic}/{queue}/print").unwrap()
+            .set_default("mqtt.topics.refreshcommand", "{root_topic}/cups_server/refresh").unwrap()
+            .set_default("mqtt.topics.outbox", "{root_topic}/cups_server/outbox").unwrap()
+            .set_default("mqtt.publish.status.qos", 1).unwrap()
+            .set_default("mqtt.publish.status.retain", true).unwrap()
+            .set_default("mqtt.publish.discovery.qos", 1).unwrap()
+            .set_default("mqtt.publish.discovery.retain", true).unwrap()
+            .set_default("mqtt.publish.events.qos", 1).unwrap()
+            .set_default("mqtt.publish.events.retain", false).unwrap()
+            .set_default("mqtt.publish.commandresponses.qos", 1).unwrap()
+            .set_default("mqtt.publish.commandresponses.retain", true).unwrap()
+            .set_default("mqtt.outbox.maxmessages", 1000).unwrap()
+            .set_default("mqtt.outbox.maxbytes", 1048576).unwrap()
+            .set_default("mqtt.printcommand.enabled", "false").unwrap()
             .set_default("mqtt.ha.enablediscovery", "false").unwrap()
             .set_default("mqtt.ha.discoverytopicprefix", "homeassistant").unwrap()
             .set_default("mqtt.ha.componentid", "cups2mqtt").unwrap()
+            .set_default("mqtt.homie.enabled", "false").unwrap()
+            .set_default("mqtt.homie.version", "v4").unwrap()
+            .set_default("mqtt.homie.basetopic", "homie").unwrap()
+            .set_default("mqtt.homie.deviceid", "cups2mqtt").unwrap()
+            .set_default("mqtt.sparkplug.enabled", "false").unwrap()
+            .set_default("mqtt.sparkplug.groupid", "cups2mqtt").unwrap()
+            .set_default("mqtt.sparkplug.edgenodeid", "cups2mqtt").unwrap()
             .set_default("cups.uri", "https://localhost:631/").unwrap()
             .set_default("cups.ignoretlserrors", "true").unwrap()
             .set_default("cups.username", "").unwrap()
             .set_default("cups.password", "").unwrap()
+            .set_default("provisioning.mode", "disabled").unwrap()
             .set_default("sentrydsn", "").unwrap()
         .build().unwrap();
 
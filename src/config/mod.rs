@@ -1,3 +1,4 @@
 pub mod models;
 pub mod loading;
 pub mod schedule;
+pub mod duration;
@@ -1,6 +1,8 @@
+use std::{collections::{BTreeMap, HashMap}, path::PathBuf};
+
 use serde_derive::Deserialize;
 
-use crate::config::schedule::TimeSchedule;
+use crate::{config::{duration::{self, HumanDuration}, schedule::TimeSchedule}, mqtt_client::fun_with_tls::TlsOptions};
 
 // When changing anything here, make sure to add
 // #[serde(alias = "ihavenounderscores")]
@@ -12,15 +14,233 @@ pub struct Mqtt {
     pub host: String,
     pub port: u16,
     pub secure: bool,
+    pub transport: MqttTransport,
+    /// Path of the WebSocket endpoint, e.g. behind a reverse proxy.
+    #[serde(alias = "websocketpath")]
+    pub websocket_path: String,
+    /// Extra HTTP headers for the WebSocket handshake, e.g. for authenticating with a reverse proxy.
+    #[serde(alias = "websocketheaders", default)]
+    pub websocket_headers: HashMap<String, String>,
     #[serde(alias = "ignoretlserrors")]
     pub ignore_tls_errors: bool,
+    /// PEM bundle of the CA(s) to verify the broker with, instead of the system certificates.
+    #[serde(alias = "cafile")]
+    pub ca_file: Option<PathBuf>,
+    /// PEM client certificate and key, for brokers which require mutual TLS.
+    #[serde(alias = "clientcertfile")]
+    pub client_cert_file: Option<PathBuf>,
+    #[serde(alias = "clientkeyfile")]
+    pub client_key_file: Option<PathBuf>,
+    /// SHA-256 fingerprint of the broker certificate or its public key, to trust (e.g. self-signed) certificates without a CA.
+    #[serde(alias = "pinnedfingerprint")]
+    pub pinned_fingerprint: Option<String>,
+    /// Name to verify the broker certificate for, when it doesn't match `host` (e.g. when connecting by IP address). Only
+    /// used for verification, the TLS handshake still asks for `host` (SNI).
+    #[serde(alias = "verifyservername")]
+    pub verify_server_name: Option<String>,
     pub username: String,
     pub password: String,
     #[serde(alias = "clientid")]
     pub client_id: String,
     #[serde(alias = "roottopic")]
     pub root_topic: String,
+    #[serde(alias = "protocolversion")]
+    pub protocol_version: MqttProtocolVersion,
+    /// MQTT v5 only: how long the broker keeps retained status messages when they aren't updated, unset to keep them forever.
+    #[serde(alias = "messageexpiry")]
+    pub message_expiry: Option<HumanDuration>,
+    /// How long a queue must be gone from CUPS before its retained messages are removed, `off` to never remove them.
+    #[serde(default, alias = "removedqueuegraceperiod", deserialize_with = "duration::deserialize_optional")]
+    pub removed_queue_grace_period: Option<HumanDuration>,
+    /// Also publish every field of a queue status on its own sub-topic, for consumers which can't parse JSON.
+    #[serde(alias = "flattopics")]
+    pub flat_topics: bool,
+    /// Also publish when CUPS was polled on a `polled_at` sub-topic of the server and queue status, on every poll.
+    #[serde(alias = "publishpolledat")]
+    pub publish_polled_at: bool,
+    /// Name in topics by queue name, for queues of which the name doesn't work well in topics.
+    #[serde(default, alias = "queuetopicnames")]
+    pub queue_topic_names: HashMap<String, String>,
+    /// Encoding of the JSON status and event payloads.
+    pub codec: PayloadCodec,
+    pub topics: Topics,
+    /// Max age of unchanged messages by topic filter, overriding the max age of their class.
+    #[serde(default, alias = "maxage")]
+    pub max_age: HashMap<String, HumanDuration>,
+    #[serde(default)]
+    pub templates: Templates,
+    pub publish: PublishPolicies,
+    pub outbox: Outbox,
+    #[serde(alias = "printcommand")]
+    pub print_command: PrintCommand,
     pub ha: HomeAssistant,
+    pub homie: Homie,
+    pub sparkplug: Sparkplug,
+}
+
+/// Topic templates, in which `{root_topic}`, `{server}` (the CUPS host), `{queue}` and `{job_id}` are replaced.
+#[derive(Debug, Deserialize)]
+#[allow(unused)]
+pub struct Topics {
+    #[serde(alias = "serverstatus")]
+    pub server_status: String,
+    #[serde(alias = "queuestatus")]
+    pub queue_status: String,
+    #[serde(alias = "stuckjobs")]
+    pub stuck_jobs: String,
+    pub failover: String,
+    #[serde(alias = "failoverevents")]
+    pub failover_events: String,
+    pub provisioning: String,
+    pub audit: String,
+    pub job: String,
+    #[serde(alias = "jobevents")]
+    pub job_events: String,
+    pub devices: String,
+    #[serde(alias = "scandevicescommand")]
+    pub scan_devices_command: String,
+    #[serde(alias = "printcommand")]
+    pub print_command: String,
+    #[serde(alias = "refreshcommand")]
+    pub refresh_command: String,
+    pub outbox: String,
+}
+
+/// Jinja-like templates replacing the JSON payload of a topic, unset to publish the default JSON.
+#[derive(Debug, Deserialize, Default)]
+#[allow(unused)]
+pub struct Templates {
+    #[serde(alias = "serverstatus")]
+    pub server_status: Option<String>,
+    #[serde(alias = "queuestatus")]
+    pub queue_status: Option<String>,
+}
+
+/// Buffer for events published while the broker can't be reached, which are published after reconnecting.
+#[derive(Debug, Deserialize)]
+#[allow(unused)]
+pub struct Outbox {
+    /// The oldest events are dropped when more are buffered, 0 disables the outbox.
+    #[serde(alias = "maxmessages")]
+    pub max_messages: usize,
+    /// The oldest events are dropped when the payloads of the buffered events take more bytes.
+    #[serde(alias = "maxbytes")]
+    pub max_bytes: usize,
+    /// File to keep the buffered events in, so they survive a restart. Unset to keep them in memory only.
+    pub file: Option<PathBuf>,
+}
+
+/// Submitting documents published to the print command topic as print jobs.
+#[derive(Debug, Deserialize)]
+#[allow(unused)]
+pub struct PrintCommand {
+    /// Off by default, as anyone who can publish to the broker can then print.
+    pub enabled: bool,
+}
+
+/// How each class of messages is published.
+#[derive(Debug, Deserialize)]
+#[allow(unused)]
+pub struct PublishPolicies {
+    pub status: PublishPolicy,
+    pub discovery: PublishPolicy,
+    pub events: PublishPolicy,
+    #[serde(alias = "commandresponses")]
+    pub command_responses: PublishPolicy,
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(unused)]
+pub struct PublishPolicy {
+    pub qos: MqttQos,
+    pub retain: bool,
+    /// Publish unchanged messages again when they were last published this long ago, unset to never do so.
+    #[serde(alias = "maxage")]
+    pub max_age: Option<HumanDuration>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(try_from = "u8")]
+#[allow(clippy::enum_variant_names)]
+pub enum MqttQos {
+    AtMostOnce,
+    AtLeastOnce,
+    ExactlyOnce,
+}
+
+impl TryFrom<u8> for MqttQos {
+    type Error = String;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(MqttQos::AtMostOnce),
+            1 => Ok(MqttQos::AtLeastOnce),
+            2 => Ok(MqttQos::ExactlyOnce),
+            _ => Err(format!("QoS must be 0, 1 or 2, not {value}")),
+        }
+    }
+}
+
+/// How to connect to the broker, both can be combined with TLS through `secure`.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MqttTransport {
+    Tcp,
+    #[serde(alias = "websockets")]
+    WebSocket,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MqttProtocolVersion {
+    V311,
+    V5,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PayloadCodec {
+    Json,
+    Cbor,
+    #[serde(alias = "msgpack")]
+    MessagePack,
+}
+
+impl PayloadCodec {
+    /// Content type set on payloads in this encoding, over MQTT v5.
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            PayloadCodec::Json => "application/json",
+            PayloadCodec::Cbor => "application/cbor",
+            PayloadCodec::MessagePack => "application/msgpack",
+        }
+    }
+}
+
+impl Mqtt {
+    pub fn tls_options(&self) -> TlsOptions {
+        TlsOptions {
+            ignore_tls_errors: self.ignore_tls_errors,
+            ca_file: self.ca_file.clone(),
+            client_cert_file: self.client_cert_file.clone(),
+            client_key_file: self.client_key_file.clone(),
+            pinned_fingerprint: self.pinned_fingerprint.clone(),
+            verify_server_name: self.verify_server_name.clone(),
+        }
+    }
+
+    /// Topic with `online` or `offline`, the latter being the last will. There can only be one last will, so there's no
+    /// availability topic when Homie or Sparkplug needs it, as `online` would stay when cups2mqtt disappears.
+    pub fn availability_topic(&self) -> Option<String> {
+        (!self.homie.enabled && !self.sparkplug.enabled).then(|| format!("{}/availability", self.root_topic))
+    }
+
+    pub fn topic_name_for_queue(&self, queue_name: &str) -> Option<&str> {
+        // CUPS queue names are case insensitive.
+        self.queue_topic_names.iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(queue_name))
+            .map(|(_, topic_name)| topic_name.as_str())
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -32,6 +252,65 @@ pub struct HomeAssistant {
     pub discovery_topic_prefix: String,
     #[serde(alias = "componentid")]
     pub component_id: String,
+    /// Makes the print server and queue sensors unavailable when not updated for this long, unset to never do so.
+    #[serde(alias = "expireafter")]
+    pub expire_after: Option<HumanDuration>,
+}
+
+/// Publishing according to the Homie convention, as a device with a node per print queue.
+#[derive(Debug, Deserialize)]
+#[allow(unused)]
+pub struct Homie {
+    pub enabled: bool,
+    pub version: HomieVersion,
+    #[serde(alias = "basetopic")]
+    pub base_topic: String,
+    /// Only lowercase letters, digits and hyphens are allowed.
+    #[serde(alias = "deviceid")]
+    pub device_id: String,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum HomieVersion {
+    V4,
+    V5,
+}
+
+/// Publishing according to Sparkplug B, as an edge node with a device per print queue.
+#[derive(Debug, Deserialize)]
+#[allow(unused)]
+pub struct Sparkplug {
+    pub enabled: bool,
+    #[serde(alias = "groupid")]
+    pub group_id: String,
+    #[serde(alias = "edgenodeid")]
+    pub edge_node_id: String,
+}
+
+impl Homie {
+    /// Topic of the device, below which all its attributes, nodes and properties are published.
+    pub fn device_topic(&self) -> String {
+        match self.version {
+            HomieVersion::V4 => format!("{}/{}", self.base_topic, self.device_id),
+            HomieVersion::V5 => format!("{}/5/{}", self.base_topic, self.device_id),
+        }
+    }
+
+    /// Topic with the state of the device, `lost` being the last will when Homie is enabled.
+    pub fn state_topic(&self) -> String {
+        format!("{}/$state", self.device_topic())
+    }
+}
+
+impl Sparkplug {
+    /// Topic of a message of the edge node, like `NBIRTH`, or of one of its devices, like `DDATA`.
+    pub fn topic(&self, message_type: &str, device_id: Option<&str>) -> String {
+        match device_id {
+            Some(device_id) => format!("spBv1.0/{}/{message_type}/{}/{device_id}", self.group_id, self.edge_node_id),
+            None => format!("spBv1.0/{}/{message_type}/{}", self.group_id, self.edge_node_id),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -40,10 +319,190 @@ pub struct Cups {
     pub uri: String,
     #[serde(alias = "ignoretlserrors")]
     pub ignore_tls_errors: bool,
+    /// PEM bundle of the CA(s) to verify CUPS with, instead of the system certificates.
+    #[serde(alias = "cafile")]
+    pub ca_file: Option<PathBuf>,
+    /// PEM client certificate and key, for CUPS servers which require them.
+    #[serde(alias = "clientcertfile")]
+    pub client_cert_file: Option<PathBuf>,
+    #[serde(alias = "clientkeyfile")]
+    pub client_key_file: Option<PathBuf>,
+    /// SHA-256 fingerprint of the CUPS certificate or its public key, trusted even when `ignore_tls_errors` is set.
+    #[serde(alias = "pinnedfingerprint")]
+    pub pinned_fingerprint: Option<String>,
     pub username: String,
     pub password: String,
     #[serde(alias = "reportsupplylevelsschedule")]
     pub report_supply_levels_schedule: Option<TimeSchedule>,
+    #[serde(alias = "scandevicesschedule")]
+    pub scan_devices_schedule: Option<TimeSchedule>,
+}
+
+impl Cups {
+    pub fn tls_options(&self) -> TlsOptions {
+        TlsOptions {
+            ignore_tls_errors: self.ignore_tls_errors,
+            ca_file: self.ca_file.clone(),
+            client_cert_file: self.client_cert_file.clone(),
+            client_key_file: self.client_key_file.clone(),
+            pinned_fingerprint: self.pinned_fingerprint.clone(),
+            verify_server_name: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(unused)]
+pub struct Provisioning {
+    pub mode: ProvisioningMode,
+    #[serde(default)]
+    pub queues: Vec<ProvisionedQueue>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProvisioningMode {
+    /// Don't look at the configured queues at all.
+    Disabled,
+    /// Only report drift between the configured queues and CUPS.
+    Report,
+    /// Report drift and create or update queues to match the configuration.
+    Enforce,
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(unused)]
+pub struct ProvisionedQueue {
+    pub name: String,
+    #[serde(alias = "deviceuri")]
+    pub device_uri: String,
+    /// PPD or driver name as listed by `lpinfo -m`, use `everywhere` for IPP Everywhere. Only used when creating the queue.
+    #[serde(alias = "ppdname")]
+    pub ppd_name: Option<String>,
+    pub description: Option<String>,
+    pub location: Option<String>,
+    pub shared: Option<bool>,
+    pub accepting: Option<bool>,
+    /// Job template defaults, e.g. `media = "om_w288h432_101.6x152.4mm"` for `media-default`.
+    #[serde(default)]
+    pub defaults: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[allow(unused)]
+pub struct StuckJobs {
+    /// How long a job may be processing or held without making progress, unset disables detection.
+    pub threshold: Option<HumanDuration>,
+    /// Per queue overrides of `threshold`.
+    #[serde(default)]
+    pub queues: HashMap<String, HumanDuration>,
+}
+
+impl StuckJobs {
+    pub fn threshold_for_queue(&self, queue_name: &str) -> Option<HumanDuration> {
+        // CUPS queue names are case insensitive.
+        self.queues.iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(queue_name))
+            .map(|(_, threshold)| *threshold)
+            .or(self.threshold)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.threshold.is_some() || !self.queues.is_empty()
+    }
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[allow(unused)]
+pub struct Recovery {
+    /// Resume after of the default policy, which applies to all queues when set.
+    #[serde(alias = "resumeafter")]
+    pub resume_after: Option<HumanDuration>,
+    /// Retry job after attempts of the default policy.
+    #[serde(alias = "retryjobafterattempts")]
+    pub retry_job_after_attempts: Option<u32>,
+    /// Cancel job after attempts of the default policy.
+    #[serde(alias = "canceljobafterattempts")]
+    pub cancel_job_after_attempts: Option<u32>,
+    /// Per queue policies, overriding the default policy.
+    #[serde(default)]
+    pub queues: HashMap<String, RecoveryPolicy>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+#[allow(unused)]
+pub struct RecoveryPolicy {
+    /// How long to wait after the queue stopped, or after the previous attempt, before resuming the queue.
+    #[serde(alias = "resumeafter")]
+    pub resume_after: HumanDuration,
+    /// Retry the current job after this many failed resume attempts, unset to never retry jobs.
+    #[serde(alias = "retryjobafterattempts")]
+    pub retry_job_after_attempts: Option<u32>,
+    /// Cancel the current job after this many failed resume attempts, unset to never cancel jobs.
+    #[serde(alias = "canceljobafterattempts")]
+    pub cancel_job_after_attempts: Option<u32>,
+}
+
+impl Recovery {
+    pub fn policy_for_queue(&self, queue_name: &str) -> Option<RecoveryPolicy> {
+        // CUPS queue names are case insensitive.
+        self.queues.iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(queue_name))
+            .map(|(_, policy)| policy.clone())
+            .or_else(|| self.default_policy())
+    }
+
+    /// The policy for queues without their own policy, when `resume_after` is set.
+    pub fn default_policy(&self) -> Option<RecoveryPolicy> {
+        self.resume_after.map(|resume_after| RecoveryPolicy {
+            resume_after,
+            retry_job_after_attempts: self.retry_job_after_attempts,
+            cancel_job_after_attempts: self.cancel_job_after_attempts,
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.resume_after.is_some() || !self.queues.is_empty()
+    }
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[allow(unused)]
+pub struct Failover {
+    /// Failover policies, keyed by the name of the primary queue.
+    #[serde(default)]
+    pub queues: HashMap<String, FailoverPolicy>,
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(unused)]
+pub struct FailoverPolicy {
+    /// The queue to move the pending jobs to.
+    pub standby: String,
+    /// How long the primary queue must be unhealthy before failing over.
+    pub after: Option<HumanDuration>,
+    #[serde(alias = "onrecovery", default)]
+    pub on_recovery: FailbackMode,
+}
+
+#[derive(Debug, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FailbackMode {
+    /// Leave the moved jobs on the standby queue.
+    #[default]
+    Stay,
+    /// Move the moved jobs which are still pending back to the primary queue.
+    MoveBack,
+}
+
+impl Failover {
+    pub fn policy_for_queue(&self, queue_name: &str) -> Option<&FailoverPolicy> {
+        // CUPS queue names are case insensitive.
+        self.queues.iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(queue_name))
+            .map(|(_, policy)| policy)
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -53,6 +512,13 @@ pub struct Settings {
     pub polling_schedule: TimeSchedule,
     pub mqtt: Mqtt,
     pub cups: Cups,
+    pub provisioning: Provisioning,
+    #[serde(alias = "stuckjobs", default)]
+    pub stuck_jobs: StuckJobs,
+    #[serde(default)]
+    pub recovery: Recovery,
+    #[serde(default)]
+    pub failover: Failover,
     #[serde(alias = "sentrydsn")]
     pub sentry_dsn: Option<String>,
 }
@@ -8,7 +8,7 @@ use snafu::{ResultExt, Snafu};
 #[derive(Debug)]
 pub enum TimeSchedule {
     Interval(Duration),
-    Cron(Cron),
+    Cron(Box<Cron>),
 }
 
 impl TimeSchedule {
@@ -41,7 +41,7 @@ impl<'de> Deserialize<'de> for TimeSchedule {
             {
                 // First try parsing as cron syntax.
                 if let Ok(cron) = Cron::from_str(value) {
-                    return Ok(TimeSchedule::Cron(cron));
+                    return Ok(TimeSchedule::Cron(Box::new(cron)));
                 }
 
                 // Now try humantime (e.g. '30m' for 30 minutes).
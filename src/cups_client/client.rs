@@ -1,10 +1,10 @@
-use std::{collections::HashMap, io::Cursor};
+use std::{collections::HashMap, io::{Cursor, Read}, sync::OnceLock, time::Duration};
 
-use ipp::{prelude::*, value::BoundedString};
+use ipp::{parser::IppParser, prelude::*, reader::IppReader, value::{BoundedString, IppTextValue}};
 use snafu::{whatever, OptionExt, ResultExt, Snafu};
 use url::Url;
 
-use crate::config::models::Cups;
+use crate::{config::models::Cups, mqtt_client::fun_with_tls::{build_client_config, TlsOptions}};
 
 use super::models::{*};
 
@@ -12,12 +12,12 @@ use super::models::{*};
 // Print queues //
 // //////////// //
 
-pub async fn get_raw_print_queues(uri: String, ignore_tls_errors: bool) -> Result<IppRequestResponse, CupsError> {
-    send_ipp_request(uri.clone(), ignore_tls_errors, Operation::CupsGetPrinters).await
+pub async fn get_raw_print_queues(uri: String, tls: &TlsOptions) -> Result<IppRequestResponse, CupsError> {
+    send_ipp_request(uri.clone(), tls, Operation::CupsGetPrinters).await
 }
 
-pub async fn get_print_queues(uri: String, ignore_tls_errors: bool) -> Result<Vec<IppPrintQueueState>, CupsError> {
-    let resp = get_raw_print_queues(uri, ignore_tls_errors).await;
+pub async fn get_print_queues(uri: String, tls: &TlsOptions) -> Result<Vec<IppPrintQueueState>, CupsError> {
+    let resp = get_raw_print_queues(uri, tls).await;
     let mut vec: Vec<IppPrintQueueState> = Vec::new();
 
     for printer in resp?.attributes().groups_of(DelimiterTag::PrinterAttributes) {
@@ -26,13 +26,20 @@ pub async fn get_print_queues(uri: String, ignore_tls_errors: bool) -> Result<Ve
             .value()
             .as_enum()
             .and_then(|v| PrinterState::from_i32(*v)).with_whatever_context(|| "Failed to parse printer state")?;
-        let job_count = group["queued-job-count"].value().as_integer().with_whatever_context(|| "Failed to parse job count")?.clone();
+        let job_count = *group["queued-job-count"].value().as_integer().with_whatever_context(|| "Failed to parse job count")?;
         let state_message = group["printer-state-message"].value().to_string().clone();
         let queue_name = group["printer-name"].value().to_string().clone();
         let description = group["printer-info"].value().to_string().clone();
         let printer_make = group["printer-make-and-model"].value().to_string().clone();
         let state_reason = group["printer-state-reasons"].value().to_string().clone();
         let cups_version = group["cups-version"].value().to_string().clone();
+        let location = group.get("printer-location").map(|v| v.value().to_string()).unwrap_or_default();
+        let device_uri = group.get("device-uri").map(|v| v.value().to_string());
+        let is_accepting_jobs = group.get("printer-is-accepting-jobs").and_then(|v| v.value().as_boolean().copied()).unwrap_or(true);
+        let is_shared = group.get("printer-is-shared").and_then(|v| v.value().as_boolean().copied()).unwrap_or(false);
+        let defaults = group.iter()
+            .filter_map(|(name, attribute)| name.strip_suffix("-default").map(|name| (name.to_owned(), attribute.value().to_string())))
+            .collect();
 
         let mut markers = Vec::<IppPrinterMarker>::new();
 
@@ -43,12 +50,7 @@ pub async fn get_print_queues(uri: String, ignore_tls_errors: bool) -> Result<Ve
         let marker_names = get_ipp_strings(&group, "marker-names");
         let marker_levels = get_ipp_ints(&group, "marker-levels");
 
-        if marker_types.is_ok() && marker_colors.is_ok() && marker_names.is_ok() && marker_levels.is_ok() {
-            let marker_types = marker_types.unwrap();
-            let marker_colors = marker_colors.unwrap();
-            let marker_names = marker_names.unwrap();
-            let marker_levels = marker_levels.unwrap();
-
+        if let (Ok(marker_types), Ok(marker_colors), Ok(marker_names), Ok(marker_levels)) = (marker_types, marker_colors, marker_names, marker_levels) {
             for i in 0..marker_types.len() {
                 let marker_level = marker_levels[i];
                 let marker_color = marker_colors[i].clone();
@@ -68,7 +70,36 @@ pub async fn get_print_queues(uri: String, ignore_tls_errors: bool) -> Result<Ve
             }
         }
 
-        vec.push(IppPrintQueueState { queue_name, description, printer_make, state, job_count, state_message, state_reason, cups_version, markers });
+        vec.push(IppPrintQueueState { queue_name, description, location, printer_make, device_uri, state, is_accepting_jobs, is_shared, job_count, state_message, state_reason, cups_version, markers, defaults });
+    }
+
+    Ok(vec)
+}
+
+// /////// //
+// Devices //
+// /////// //
+
+/// Lists the devices the CUPS backends can find (like `lpinfo -v`), including devices without a queue.
+pub async fn get_devices(uri: String, tls: &TlsOptions) -> Result<Vec<IppDevice>, CupsError> {
+    let resp = send_ipp_request(uri, tls, Operation::CupsGetDevices).await?;
+    if !resp.header().status_code().is_success() {
+        whatever!("CUPS-Get-Devices failed with status code [{}]", resp.header().status_code())
+    }
+
+    let mut vec: Vec<IppDevice> = Vec::new();
+    for device in resp.attributes().groups_of(DelimiterTag::PrinterAttributes) {
+        let group = device.attributes();
+        let get_string = |name: &str| group.get(name).map(|v| v.value().to_string()).filter(|v| !v.is_empty());
+
+        vec.push(IppDevice {
+            device_uri: get_string("device-uri").with_whatever_context(|| "Device without URI in CUPS-Get-Devices response")?,
+            device_class: get_string("device-class").unwrap_or_default(),
+            info: get_string("device-info"),
+            make_and_model: get_string("device-make-and-model"),
+            device_id: get_string("device-id"),
+            location: get_string("device-location"),
+        });
     }
 
     Ok(vec)
@@ -78,25 +109,173 @@ pub async fn get_print_queues(uri: String, ignore_tls_errors: bool) -> Result<Ve
 // Printing and commands //
 // ///////////////////// //
 
-pub async fn report_supply_levels(uri: String, ignore_tls_errors: bool) -> Result<(), CupsError> {
+pub async fn report_supply_levels(uri: String, tls: &TlsOptions) -> Result<(), CupsError> {
     let command = "#CUPS-COMMAND\nReportLevels";
     let command_bytes: Vec<u8> = command.as_bytes().to_vec();
-    print_job(uri, ignore_tls_errors, "CUPS2MQTT update supply levels".to_owned(), command_bytes).await?;
+    print_job(uri, tls, "CUPS2MQTT update supply levels".to_owned(), command_bytes).await?;
     Ok(())
 }
 
-pub async fn print_job(uri: String, ignore_tls_errors: bool, job_name: String, job_data: Vec<u8>) -> Result<(), CupsError> {
+/// Submits `job_data` as a new job to the print queue at `uri` and returns the job ID.
+pub async fn print_job(uri: String, tls: &TlsOptions, job_name: String, job_data: Vec<u8>) -> Result<i32, CupsError> {
     let uri_p: Uri = uri.parse::<Uri>().with_whatever_context(|_| format!("Could not parse URI {uri}"))?.clone();
     let pdf_data_cursor = Cursor::new(job_data);
     let pdf_data_payload = IppPayload::new(pdf_data_cursor);
     let print_job_builder = IppOperationBuilder::print_job(uri_p.clone(), pdf_data_payload).job_title(job_name);
     let print_job = print_job_builder.build().with_whatever_context(|_| "Failed to build IPP print job")?;
 
-    let client = AsyncIppClient::builder(uri_p).ignore_tls_errors(ignore_tls_errors).build();
-    let resp = client.send(print_job).await.with_whatever_context(|_| format!("IPP request failed"))?;
+    let resp = send(&uri_p, tls, print_job).await.with_whatever_context(|_| "IPP request failed")?;
     if !resp.header().status_code().is_success() {
         whatever!("IPP request failed with status code [{}]", resp.header().status_code())
     }
+
+    let job_id = resp.attributes().groups_of(DelimiterTag::JobAttributes)
+        .find_map(|group| group.attributes().get(IppAttribute::JOB_ID))
+        .and_then(|job_id| job_id.value().as_integer().copied())
+        .with_whatever_context(|| "No job ID in Print-Job response")?;
+    Ok(job_id)
+}
+
+// //// //
+// Jobs //
+// //// //
+
+/// Gets the current state of job `job_id` on the print queue at `uri`.
+/// Returns `None` when CUPS doesn't know the job (anymore), e.g. because the job history has been purged.
+pub async fn get_job(uri: String, tls: &TlsOptions, job_id: i32) -> Result<Option<IppJob>, CupsError> {
+    let attributes = vec![(DelimiterTag::OperationAttributes, build_ipp_attribute(IppAttribute::JOB_ID, IppValue::Integer(job_id))?)];
+    let resp = send_ipp_request_with_attributes(uri, tls, Operation::GetJobAttributes, attributes).await?;
+    match resp.header().status_code() {
+        StatusCode::ClientErrorNotFound => return Ok(None),
+        status_code if !status_code.is_success() => whatever!("Get-Job-Attributes for job {job_id} failed with status code [{status_code}]"),
+        _ => {},
+    }
+
+    let group = resp.attributes().groups_of(DelimiterTag::JobAttributes).next().with_whatever_context(|| format!("No job attributes for job {job_id} in response"))?;
+    Ok(Some(parse_ipp_job(group.attributes())?))
+}
+
+/// Gets the jobs on the print queue at `uri` which are not completed yet.
+pub async fn get_jobs(uri: String, tls: &TlsOptions) -> Result<Vec<IppJob>, CupsError> {
+    // Without `requested-attributes` only the job ID and URI are returned.
+    let requested_attributes = [IppAttribute::JOB_ID, "job-name", IppAttribute::JOB_STATE, IppAttribute::JOB_STATE_REASONS, "job-printer-state-message", "job-impressions-completed"]
+        .into_iter()
+        .map(|name| build_ipp_string(name).map(IppValue::Keyword))
+        .collect::<Result<Vec<_>, _>>()?;
+    let attributes = vec![
+        (DelimiterTag::OperationAttributes, build_ipp_attribute("which-jobs", IppValue::Keyword(build_ipp_string("not-completed")?))?),
+        (DelimiterTag::OperationAttributes, build_ipp_attribute(IppAttribute::REQUESTED_ATTRIBUTES, IppValue::Array(requested_attributes))?),
+    ];
+
+    let resp = send_ipp_request_with_attributes(uri.clone(), tls, Operation::GetJobs, attributes).await?;
+    if !resp.header().status_code().is_success() {
+        whatever!("Get-Jobs for {uri} failed with status code [{}]", resp.header().status_code())
+    }
+
+    resp.attributes().groups_of(DelimiterTag::JobAttributes).map(|group| parse_ipp_job(group.attributes())).collect()
+}
+
+pub async fn cancel_job(uri: String, tls: &TlsOptions, job_id: i32) -> Result<(), CupsError> {
+    let attributes = vec![(DelimiterTag::OperationAttributes, build_ipp_attribute(IppAttribute::JOB_ID, IppValue::Integer(job_id))?)];
+    let resp = send_ipp_request_with_attributes(uri, tls, Operation::CancelJob, attributes).await?;
+    if !resp.header().status_code().is_success() {
+        whatever!("Cancel-Job for job {job_id} failed with status code [{}]", resp.header().status_code())
+    }
+    Ok(())
+}
+
+/// Prints a stopped, completed, canceled or aborted job again from the start.
+pub async fn restart_job(uri: String, tls: &TlsOptions, job_id: i32) -> Result<(), CupsError> {
+    let attributes = vec![(DelimiterTag::OperationAttributes, build_ipp_attribute(IppAttribute::JOB_ID, IppValue::Integer(job_id))?)];
+    let resp = send_ipp_request_with_attributes(uri, tls, Operation::RestartJob, attributes).await?;
+    if !resp.header().status_code().is_success() {
+        whatever!("Restart-Job for job {job_id} failed with status code [{}]", resp.header().status_code())
+    }
+    Ok(())
+}
+
+/// Releases a held job, so it's printed again.
+pub async fn release_job(uri: String, tls: &TlsOptions, job_id: i32) -> Result<(), CupsError> {
+    let attributes = vec![(DelimiterTag::OperationAttributes, build_ipp_attribute(IppAttribute::JOB_ID, IppValue::Integer(job_id))?)];
+    let resp = send_ipp_request_with_attributes(uri, tls, Operation::ReleaseJob, attributes).await?;
+    if !resp.header().status_code().is_success() {
+        whatever!("Release-Job for job {job_id} failed with status code [{}]", resp.header().status_code())
+    }
+    Ok(())
+}
+
+/// Moves job `job_id` from the print queue at `uri` to the print queue at `destination_uri` using CUPS-Move-Job.
+pub async fn move_job(uri: String, tls: &TlsOptions, job_id: i32, destination_uri: String) -> Result<(), CupsError> {
+    // The destination is stored with the job, so leave out the credentials.
+    let mut destination_url = Url::parse(&destination_uri).with_whatever_context(|_| format!("Could not parse URI {destination_uri}"))?;
+    let _ = destination_url.set_username("");
+    let _ = destination_url.set_password(None);
+
+    let attributes = vec![
+        (DelimiterTag::OperationAttributes, build_ipp_attribute(IppAttribute::JOB_ID, IppValue::Integer(job_id))?),
+        (DelimiterTag::JobAttributes, build_ipp_attribute("job-printer-uri", IppValue::Uri(build_ipp_string(destination_url.as_str())?))?),
+    ];
+    let resp = send_ipp_request_with_attributes(uri, tls, Operation::CupsMoveJob, attributes).await?;
+    if !resp.header().status_code().is_success() {
+        whatever!("CUPS-Move-Job for job {job_id} failed with status code [{}]", resp.header().status_code())
+    }
+    Ok(())
+}
+
+// ////////////////////////// //
+// Print queue administration //
+// ////////////////////////// //
+
+pub async fn pause_printer(uri: String, tls: &TlsOptions) -> Result<(), CupsError> {
+    let resp = send_ipp_request(uri.clone(), tls, Operation::PausePrinter).await?;
+    if !resp.header().status_code().is_success() {
+        whatever!("Pause-Printer for {uri} failed with status code [{}]", resp.header().status_code())
+    }
+    Ok(())
+}
+
+pub async fn resume_printer(uri: String, tls: &TlsOptions) -> Result<(), CupsError> {
+    let resp = send_ipp_request(uri.clone(), tls, Operation::ResumePrinter).await?;
+    if !resp.header().status_code().is_success() {
+        whatever!("Resume-Printer for {uri} failed with status code [{}]", resp.header().status_code())
+    }
+    Ok(())
+}
+
+/// Create the print queue at `uri`, or update it if it already exists, using CUPS-Add-Modify-Printer.
+/// Only the settings which are `Some` (or present in `defaults`) are sent to CUPS.
+pub async fn add_modify_printer(uri: String, tls: &TlsOptions, printer_settings: &IppPrinterSettings) -> Result<(), CupsError> {
+    let mut attributes = Vec::<IppAttribute>::new();
+    if let Some(device_uri) = &printer_settings.device_uri {
+        attributes.push(build_ipp_attribute("device-uri", IppValue::Uri(build_ipp_string(device_uri)?))?);
+    }
+    if let Some(ppd_name) = &printer_settings.ppd_name {
+        attributes.push(build_ipp_attribute("ppd-name", IppValue::NameWithoutLanguage(build_ipp_string(ppd_name)?))?);
+    }
+    if let Some(description) = &printer_settings.description {
+        attributes.push(build_ipp_attribute("printer-info", IppValue::TextWithoutLanguage(build_ipp_text(description)?))?);
+    }
+    if let Some(location) = &printer_settings.location {
+        attributes.push(build_ipp_attribute("printer-location", IppValue::TextWithoutLanguage(build_ipp_text(location)?))?);
+    }
+    if let Some(is_shared) = printer_settings.is_shared {
+        attributes.push(build_ipp_attribute("printer-is-shared", IppValue::Boolean(is_shared))?);
+    }
+    if let Some(is_accepting_jobs) = printer_settings.is_accepting_jobs {
+        attributes.push(build_ipp_attribute("printer-is-accepting-jobs", IppValue::Boolean(is_accepting_jobs))?);
+    }
+    if let Some(state) = printer_settings.state {
+        attributes.push(build_ipp_attribute("printer-state", IppValue::Enum(state as i32))?);
+    }
+    for (name, value) in &printer_settings.defaults {
+        attributes.push(build_ipp_attribute(&format!("{name}-default"), IppValue::Keyword(build_ipp_string(value)?))?);
+    }
+
+    let attributes = attributes.into_iter().map(|attribute| (DelimiterTag::PrinterAttributes, attribute)).collect();
+    let resp = send_ipp_request_with_attributes(uri.clone(), tls, Operation::CupsAddModifyPrinter, attributes).await?;
+    if !resp.header().status_code().is_success() {
+        whatever!("CUPS-Add-Modify-Printer for {uri} failed with status code [{}]", resp.header().status_code())
+    }
     Ok(())
 }
 
@@ -134,7 +313,7 @@ pub fn build_cups_url(cups_settings: &Cups, queue_id: Option<&String>) -> Result
     }
 
     Ok(match queue_id {
-        Some(queue_id) => cups_url.join("printers/").with_whatever_context(|_| "Could join ./printers/")?.join(&queue_id).with_whatever_context(|_| format!("Could not join queue ID {queue_id}"))?,
+        Some(queue_id) => cups_url.join("printers/").with_whatever_context(|_| "Could join ./printers/")?.join(queue_id).with_whatever_context(|_| format!("Could not join queue ID {queue_id}"))?,
         None => cups_url,
     }.to_string())
 }
@@ -153,21 +332,110 @@ pub fn build_cups_url(cups_settings: &Cups, queue_id: Option<&String>) -> Result
 /// ```
 /// send_ipp_request(uri, Operation::ResumePrinter).header().status_code().is_success()
 /// ```
-async fn send_ipp_request(uri: String, ignore_tls_errors: bool, op: Operation) -> Result<IppRequestResponse, CupsError> {
+async fn send_ipp_request(uri: String, tls: &TlsOptions, op: Operation) -> Result<IppRequestResponse, CupsError> {
+    send_ipp_request_with_attributes(uri, tls, op, Vec::new()).await
+}
+
+/// Like [`send_ipp_request`], but adds each of the given `attributes` to the attribute group identified by its tag.
+async fn send_ipp_request_with_attributes(uri: String, tls: &TlsOptions, op: Operation, attributes: Vec<(DelimiterTag, IppAttribute)>) -> Result<IppRequestResponse, CupsError> {
     let uri_p: Uri = uri.parse().with_whatever_context(|_| format!("Could not parse URI {uri}"))?;
-    let req = IppRequestResponse::new(
+    let mut req = IppRequestResponse::new(
         IppVersion::v2_2(),
         op,
         Some(uri_p.clone())
-    ).with_whatever_context(|_| format!("Failed to build IPP request"))?;
+    ).with_whatever_context(|_| "Failed to build IPP request")?;
+    for (tag, attribute) in attributes {
+        req.attributes_mut().add(tag, attribute);
+    }
 
     // If we ever want to specify which attributes we want to receive.
     // req.attributes_mut().groups_mut().first_mut().unwrap().attributes_mut().insert("requested-attributes".to_owned(), IppAttribute::new(IppAttribute::REQUESTED_ATTRIBUTES, IppValue::Array(vec![
     //     IppValue::Keyword("printer-name".to_owned())
     // ])));
 
-    let client = AsyncIppClient::builder(uri_p).ignore_tls_errors(ignore_tls_errors).build();
-    Ok(client.send(req).await.with_whatever_context(|_| format!("Could not send IPP request"))?)
+    send(&uri_p, tls, req).await.with_whatever_context(|_| "Could not send IPP request")
+}
+
+/// Sends `request` like `AsyncIppClient::send` does, but using our own TLS config, so certificates can be pinned.
+async fn send(uri: &Uri, tls: &TlsOptions, request: impl Into<IppRequestResponse>) -> Result<IppRequestResponse, CupsError> {
+    let client = get_http_client(tls)?;
+
+    let mut body = Vec::new();
+    request.into().into_read().read_to_end(&mut body).with_whatever_context(|_| "Could not read IPP request")?;
+    let response = client.post(build_http_uri(uri))
+        .header("content-type", "application/ipp")
+        .body(body)
+        .send().await.with_whatever_context(|_| format!("Could not connect to {}", uri.host().unwrap_or_default()))?;
+    if !response.status().is_success() {
+        whatever!("CUPS responded with HTTP status [{}]", response.status())
+    }
+
+    let response_body = response.bytes().await.with_whatever_context(|_| "Could not read IPP response")?;
+    IppParser::new(IppReader::new(Cursor::new(response_body))).parse().with_whatever_context(|_| "Could not parse IPP response")
+}
+
+/// The HTTP client for CUPS, built on first use and shared, so connections are reused. The TLS config reads changed
+/// certificate files again itself, so they don't need a new client.
+fn get_http_client(tls: &TlsOptions) -> Result<&'static reqwest::Client, CupsError> {
+    static HTTP_CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    if let Some(client) = HTTP_CLIENT.get() {
+        return Ok(client);
+    }
+
+    let tls_config = build_client_config(tls).with_whatever_context(|_| "Could not set up TLS for CUPS")?;
+    let client = reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(10))
+        // Large enough to upload a print job, but a CUPS server that stops responding doesn't block a run forever.
+        .timeout(Duration::from_secs(120))
+        .tls_backend_preconfigured(tls_config)
+        .build().with_whatever_context(|_| "Could not build HTTP client")?;
+    Ok(HTTP_CLIENT.get_or_init(|| client))
+}
+
+/// Turns an `ipp://` or `ipps://` URI into the HTTP URI to post IPP requests to.
+fn build_http_uri(uri: &Uri) -> String {
+    let (scheme, default_port) = match uri.scheme_str() {
+        Some("ipps") => ("https", 443),
+        Some("ipp") => ("http", 631),
+        _ => return uri.to_string(),
+    };
+    let Some(authority) = uri.authority() else {
+        return uri.to_string();
+    };
+    let authority = match authority.port_u16() {
+        Some(_) => authority.to_string(),
+        None => format!("{authority}:{default_port}"),
+    };
+    let path_and_query = uri.path_and_query().map(|p| p.as_str()).unwrap_or_default();
+    format!("{scheme}://{authority}{path_and_query}")
+}
+
+fn parse_ipp_job(ipp_group: &HashMap<BoundedString<255>, IppAttribute>) -> Result<IppJob, CupsError> {
+    let get_string = |name: &str| ipp_group.get(name).map(|v| v.value().to_string()).filter(|v| !v.is_empty());
+    let get_int = |name: &str| ipp_group.get(name).and_then(|v| v.value().as_integer().copied());
+
+    Ok(IppJob {
+        job_id: get_int(IppAttribute::JOB_ID).with_whatever_context(|| "Failed to parse job ID")?,
+        name: get_string("job-name").unwrap_or_default(),
+        state: ipp_group.get(IppAttribute::JOB_STATE)
+            .and_then(|v| v.value().as_enum())
+            .and_then(|v| JobState::from_i32(*v)).with_whatever_context(|| "Failed to parse job state")?,
+        state_reasons: get_ipp_strings(ipp_group, IppAttribute::JOB_STATE_REASONS).unwrap_or_default(),
+        state_message: get_string("job-printer-state-message"),
+        impressions_completed: get_int("job-impressions-completed"),
+    })
+}
+
+fn build_ipp_attribute(name: &str, value: IppValue) -> Result<IppAttribute, CupsError> {
+    IppAttribute::with_name(name, value).with_whatever_context(|_| format!("Invalid IPP attribute name {name}"))
+}
+
+fn build_ipp_string<const MAX: usize>(value: &str) -> Result<BoundedString<MAX>, CupsError> {
+    BoundedString::try_from(value).with_whatever_context(|_| format!("Value {value} is too long for an IPP attribute"))
+}
+
+fn build_ipp_text(value: &str) -> Result<IppTextValue, CupsError> {
+    IppTextValue::try_from(value).with_whatever_context(|_| format!("Value {value} is too long for an IPP attribute"))
 }
 
 // ////// //
@@ -183,3 +451,34 @@ pub enum CupsError {
         source: Option<Box<dyn std::error::Error + Send + Sync>>,
     },
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build(uri: &str) -> String {
+        build_http_uri(&uri.parse::<Uri>().unwrap())
+    }
+
+    #[test]
+    fn maps_ipp_to_http_on_port_631() {
+        assert_eq!(build("ipp://cups.local/printers/office"), "http://cups.local:631/printers/office");
+    }
+
+    #[test]
+    fn maps_ipps_to_https_on_port_443() {
+        assert_eq!(build("ipps://cups.local/printers/office"), "https://cups.local:443/printers/office");
+    }
+
+    #[test]
+    fn keeps_explicit_port_and_query() {
+        assert_eq!(build("ipp://cups.local:8631/printers/office"), "http://cups.local:8631/printers/office");
+        assert_eq!(build("ipps://192.168.1.10:631/admin?which=all"), "https://192.168.1.10:631/admin?which=all");
+    }
+
+    #[test]
+    fn leaves_other_schemes_alone() {
+        assert_eq!(build("http://cups.local:631/"), "http://cups.local:631/");
+        assert_eq!(build("https://cups.local/"), "https://cups.local/");
+    }
+}
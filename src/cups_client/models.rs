@@ -1,22 +1,79 @@
-use ipp::model::PrinterState;
+use std::collections::BTreeMap;
 
-#[derive(Debug)]
+use ipp::model::{JobState, PrinterState};
+
+#[derive(Debug, Clone)]
 pub struct IppPrintQueueState {
     pub queue_name: String,
     pub description: String,
+    pub location: String,
     pub printer_make: String,
+    pub device_uri: Option<String>,
     pub state: PrinterState,
+    pub is_accepting_jobs: bool,
+    pub is_shared: bool,
     pub job_count: i32,
     pub state_message: String,
     pub state_reason: String,
     pub cups_version: String,
     pub markers: Vec<IppPrinterMarker>,
+    /// Job template defaults (`*-default` attributes), keyed by the name without the `-default` suffix.
+    pub defaults: BTreeMap<String, String>,
 }
 
-#[derive(Debug)]
+impl IppPrintQueueState {
+    /// The `printer-state-reasons` without `none`, which CUPS gives as a list like `[paused, media-empty-error]`.
+    pub fn state_reasons(&self) -> Vec<&str> {
+        self.state_reason.trim_matches(['[', ']'])
+            .split(',')
+            .map(str::trim)
+            .filter(|reason| !reason.is_empty() && *reason != "none")
+            .collect()
+    }
+
+    /// Whether the queue was paused on purpose, e.g. with `cupsdisable` or Pause-Printer, rather than stopped by an error.
+    pub fn is_paused(&self) -> bool {
+        self.state == PrinterState::Stopped && self.state_reasons().contains(&"paused")
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct IppPrinterMarker {
     pub marker_type: String,
     pub color: Option<String>,
     pub name: String,
     pub level: Option<u32>,
 }
+
+/// Print queue settings to send with CUPS-Add-Modify-Printer. `None` leaves the current value untouched.
+#[derive(Debug, Default)]
+pub struct IppPrinterSettings {
+    pub device_uri: Option<String>,
+    pub ppd_name: Option<String>,
+    pub description: Option<String>,
+    pub location: Option<String>,
+    pub is_shared: Option<bool>,
+    pub is_accepting_jobs: Option<bool>,
+    pub state: Option<PrinterState>,
+    pub defaults: BTreeMap<String, String>,
+}
+
+#[derive(Debug)]
+pub struct IppDevice {
+    pub device_uri: String,
+    pub device_class: String,
+    pub info: Option<String>,
+    pub make_and_model: Option<String>,
+    pub device_id: Option<String>,
+    pub location: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct IppJob {
+    pub job_id: i32,
+    pub name: String,
+    pub state: JobState,
+    pub state_reasons: Vec<String>,
+    pub state_message: Option<String>,
+    pub impressions_completed: Option<i32>,
+}
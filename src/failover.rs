@@ -0,0 +1,200 @@
+use std::sync::OnceLock;
+
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use ipp::model::{JobState, PrinterState};
+use log::{error, info, warn};
+use snafu::ResultExt;
+
+use crate::{
+    config::models::{FailbackMode, FailoverPolicy},
+    cups_client::{self, models::IppPrintQueueState},
+    get_settings,
+    homie,
+    mqtt_client::models::{MqttFailoverDirection, MqttFailoverEvent, MqttFailoverStatus},
+    publish,
+    topics::{self, MessageClass},
+    ApplicationError,
+};
+
+struct FailoverState {
+    unhealthy_since: DateTime<Utc>,
+    is_failed_over: bool,
+    moved_job_ids: Vec<i32>,
+}
+
+/// Failover progress of each unhealthy primary queue, keyed by queue name.
+fn get_failover_states() -> &'static DashMap<String, FailoverState> {
+    static FAILOVER_STATES: OnceLock<DashMap<String, FailoverState>> = OnceLock::new();
+    FAILOVER_STATES.get_or_init(DashMap::new)
+}
+
+// //////// //
+// Failover //
+// //////// //
+
+/// Moves the pending jobs of stopped or out of media queues to their standby queue, and back when configured
+/// so after the primary queue recovers. The failover state is published to `<root_topic>/<queue>/failover`
+/// and every move to `<root_topic>/<queue>/failover/events`.
+pub async fn fail_over_unhealthy_queues(print_queues: &[IppPrintQueueState]) -> Result<(), ApplicationError> {
+    let settings = get_settings();
+
+    for queue in print_queues {
+        let Some(policy) = settings.failover.policy_for_queue(&queue.queue_name) else {
+            continue;
+        };
+
+        if is_unhealthy(queue) {
+            let now = Utc::now();
+            let is_due = {
+                let state = get_failover_states().entry(queue.queue_name.clone()).or_insert_with(|| FailoverState { unhealthy_since: now, is_failed_over: false, moved_job_ids: Vec::new() });
+                let after = policy.after.map(|after| after.0).unwrap_or_default();
+                (now - state.unhealthy_since).to_std().is_ok_and(|unhealthy_for| unhealthy_for >= after)
+            };
+
+            let standby_queue = print_queues.iter().find(|q| q.queue_name.eq_ignore_ascii_case(&policy.standby));
+            match standby_queue {
+                _ if !is_due => {},
+                Some(standby_queue) if !is_unhealthy(standby_queue) => fail_over(queue, policy).await?,
+                _ => warn!("Queue [{}] is unhealthy, but standby queue [{}] is unhealthy or missing too", queue.queue_name, policy.standby),
+            }
+        } else if let Some((_, state)) = get_failover_states().remove(&queue.queue_name) && state.is_failed_over {
+            info!("Queue [{}] recovered, failing back", queue.queue_name);
+            if policy.on_recovery == FailbackMode::MoveBack {
+                fail_back(queue, policy, &state.moved_job_ids).await?;
+            }
+        }
+
+        if topics::is_colliding(&queue.queue_name) {
+            continue;
+        }
+        let (is_failed_over, moved_job_ids) = match get_failover_states().get(&queue.queue_name) {
+            Some(state) => (state.is_failed_over, state.moved_job_ids.clone()),
+            None => (false, Vec::new()),
+        };
+        let topic = topics::build_topic(&settings.mqtt.topics.failover, Some(&queue.queue_name), None);
+        let payload = serde_json::to_string(&MqttFailoverStatus { is_failed_over, standby_queue: policy.standby.clone(), moved_job_ids })
+            .with_whatever_context(|_| format!("Could not serialize failover status for topic {topic}"))?;
+        publish(MessageClass::Status, &topic, payload).await?;
+    }
+
+    Ok(())
+}
+
+async fn fail_over(queue: &IppPrintQueueState, policy: &FailoverPolicy) -> Result<(), ApplicationError> {
+    // Jobs which arrive while failed over are moved as well.
+    let moved_job_ids = move_jobs(&queue.queue_name, &policy.standby, |job_state, _| matches!(job_state, JobState::Pending | JobState::Processing | JobState::ProcessingStopped)).await?;
+
+    let is_new_failover = match get_failover_states().get_mut(&queue.queue_name) {
+        Some(mut state) => {
+            state.moved_job_ids.extend(&moved_job_ids);
+            !std::mem::replace(&mut state.is_failed_over, true)
+        },
+        None => false,
+    };
+
+    if is_new_failover {
+        warn!("Queue [{}] failed over to [{}] ({}), moved job(s) {:?}", queue.queue_name, policy.standby, queue.state_message, moved_job_ids);
+    }
+    if is_new_failover || !moved_job_ids.is_empty() {
+        publish_failover_event(&queue.queue_name, &policy.standby, MqttFailoverDirection::Failover, moved_job_ids).await?;
+    }
+
+    Ok(())
+}
+
+async fn fail_back(queue: &IppPrintQueueState, policy: &FailoverPolicy, moved_job_ids: &[i32]) -> Result<(), ApplicationError> {
+    // Only move back jobs which the standby queue didn't start on yet.
+    let moved_back_job_ids = move_jobs(&policy.standby, &queue.queue_name, |job_state, job_id| job_state == JobState::Pending && moved_job_ids.contains(&job_id)).await?;
+    info!("Moved job(s) {:?} back from [{}] to [{}]", moved_back_job_ids, policy.standby, queue.queue_name);
+    publish_failover_event(&queue.queue_name, &policy.standby, MqttFailoverDirection::Failback, moved_back_job_ids).await
+}
+
+// /////// //
+// Helpers //
+// /////// //
+
+/// Queues paused on purpose count as healthy, so their jobs wait for them to be resumed.
+fn is_unhealthy(queue: &IppPrintQueueState) -> bool {
+    !homie::is_paused_on_purpose(queue) && (queue.state == PrinterState::Stopped || queue.state_reason.contains("media-empty"))
+}
+
+/// Moves the jobs of `source_queue_name` selected by `filter` to `destination_queue_name` and returns the IDs of the moved jobs.
+async fn move_jobs(source_queue_name: &str, destination_queue_name: &str, filter: impl Fn(JobState, i32) -> bool) -> Result<Vec<i32>, ApplicationError> {
+    let settings = get_settings();
+    let source_uri = cups_client::client::build_cups_url(&settings.cups, Some(&source_queue_name.to_owned())).with_whatever_context(|_| "Could not build CUPS URL")?;
+    let destination_uri = cups_client::client::build_cups_url(&settings.cups, Some(&destination_queue_name.to_owned())).with_whatever_context(|_| "Could not build CUPS URL")?;
+
+    let jobs = cups_client::client::get_jobs(source_uri.clone(), &settings.cups.tls_options()).await.with_whatever_context(|_| format!("Could not get jobs of queue {source_queue_name}"))?;
+    let mut moved_job_ids = Vec::<i32>::new();
+    for job in jobs.iter().filter(|job| filter(job.state, job.job_id)) {
+        match cups_client::client::move_job(source_uri.clone(), &settings.cups.tls_options(), job.job_id, destination_uri.clone()).await {
+            Ok(_) => moved_job_ids.push(job.job_id),
+            Err(e) => error!("Failed to move job {} from [{source_queue_name}] to [{destination_queue_name}]: {e}", job.job_id),
+        }
+    }
+
+    Ok(moved_job_ids)
+}
+
+async fn publish_failover_event(queue_name: &str, standby_queue_name: &str, direction: MqttFailoverDirection, job_ids: Vec<i32>) -> Result<(), ApplicationError> {
+    let settings = get_settings();
+
+    let topic = topics::build_topic(&settings.mqtt.topics.failover_events, Some(queue_name), None);
+    let payload = serde_json::to_string(&MqttFailoverEvent {
+        timestamp: Utc::now().to_rfc3339(),
+        queue: queue_name.to_owned(),
+        standby_queue: standby_queue_name.to_owned(),
+        direction,
+        job_ids,
+    }).with_whatever_context(|_| format!("Could not serialize failover event for topic {topic}"))?;
+    publish(MessageClass::Event, &topic, payload).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build_queue(state: PrinterState, state_reason: &str) -> IppPrintQueueState {
+        IppPrintQueueState {
+            queue_name: "office".to_owned(),
+            description: String::new(),
+            location: String::new(),
+            printer_make: String::new(),
+            device_uri: None,
+            state,
+            is_accepting_jobs: true,
+            is_shared: false,
+            job_count: 1,
+            state_message: String::new(),
+            state_reason: state_reason.to_owned(),
+            cups_version: String::new(),
+            markers: Vec::new(),
+            defaults: Default::default(),
+        }
+    }
+
+    #[test]
+    fn stopped_queue_is_unhealthy() {
+        assert!(is_unhealthy(&build_queue(PrinterState::Stopped, "none")));
+        assert!(is_unhealthy(&build_queue(PrinterState::Stopped, "offline-report")));
+    }
+
+    #[test]
+    fn queue_out_of_media_is_unhealthy() {
+        assert!(is_unhealthy(&build_queue(PrinterState::Idle, "media-empty-error")));
+        assert!(is_unhealthy(&build_queue(PrinterState::Processing, "[media-empty-warning, toner-low-report]")));
+    }
+
+    #[test]
+    fn idle_or_processing_queue_is_healthy() {
+        assert!(!is_unhealthy(&build_queue(PrinterState::Idle, "none")));
+        assert!(!is_unhealthy(&build_queue(PrinterState::Processing, "toner-low-report")));
+    }
+
+    #[test]
+    fn paused_queue_is_healthy() {
+        assert!(!is_unhealthy(&build_queue(PrinterState::Stopped, "paused")));
+        assert!(!is_unhealthy(&build_queue(PrinterState::Stopped, "[paused, media-empty-error]")));
+    }
+}
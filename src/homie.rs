@@ -0,0 +1,350 @@
+use std::{collections::BTreeMap, hash::{DefaultHasher, Hash, Hasher}, sync::OnceLock};
+
+use dashmap::{DashMap, DashSet};
+use ipp::model::PrinterState;
+use snafu::{whatever, ResultExt};
+
+use crate::{
+    build_message_properties,
+    config::models::HomieVersion,
+    cups_client::{self, client::CupsError, models::IppPrintQueueState},
+    get_last_published_mqtt_messages,
+    get_settings,
+    mqtt_client::{client::MqttMessageProperties, models::{HomieDeviceDescription, HomieNodeDescription, HomiePropertyDescription}},
+    publish_status_with_properties,
+    publish_with_properties,
+    topics::{self, MessageClass},
+    ApplicationError,
+};
+
+const NODE_CUPS_SERVER: &str = "cups-server";
+const PROPERTY_PAUSED: &str = "paused";
+
+/// The queue name of each queue node, keyed by node ID, to find the queue a `set` message is for.
+fn get_queue_nodes() -> &'static DashMap<String, String> {
+    static QUEUE_NODES: OnceLock<DashMap<String, String>> = OnceLock::new();
+    QUEUE_NODES.get_or_init(DashMap::new)
+}
+
+/// Queues paused through the `paused` property, which recovery and failover leave alone until they're resumed.
+fn get_paused_queues() -> &'static DashSet<String> {
+    static PAUSED_QUEUES: OnceLock<DashSet<String>> = OnceLock::new();
+    PAUSED_QUEUES.get_or_init(DashSet::new)
+}
+
+// ////////// //
+// Publishing //
+// ////////// //
+
+/// Publishes the CUPS server as Homie device, with a node for the server itself and one for every print queue. When CUPS
+/// can't be reached, only `reachable` is updated, so the queue nodes stay as they were.
+pub async fn publish_homie_device(print_queues_result: &Result<Vec<IppPrintQueueState>, CupsError>) -> Result<(), ApplicationError> {
+    let settings = get_settings();
+    let device_topic = settings.mqtt.homie.device_topic();
+
+    let Ok(print_queues) = print_queues_result else {
+        return publish_value(&format!("{device_topic}/{NODE_CUPS_SERVER}/reachable"), Some("false".to_owned())).await;
+    };
+
+    let mut nodes = BTreeMap::<String, HomieNodeDescription>::new();
+    let mut values = Vec::<(String, Option<String>)>::new();
+
+    nodes.insert(NODE_CUPS_SERVER.to_owned(), HomieNodeDescription {
+        name: "CUPS server".to_owned(),
+        node_type: "print-server".to_owned(),
+        properties: BTreeMap::from([
+            ("reachable".to_owned(), build_property("Reachable", "boolean")),
+            ("cups-version".to_owned(), build_property("CUPS version", "string")),
+            ("cups2mqtt-version".to_owned(), build_property("CUPS2MQTT version", "string")),
+        ]),
+    });
+    values.push((format!("{NODE_CUPS_SERVER}/reachable"), Some("true".to_owned())));
+    values.push((format!("{NODE_CUPS_SERVER}/cups-version"), print_queues.first().map(|q| q.cups_version.clone())));
+    values.push((format!("{NODE_CUPS_SERVER}/cups2mqtt-version"), Some(env!("CARGO_PKG_VERSION").to_owned())));
+
+    get_queue_nodes().clear();
+    for queue in print_queues {
+        if topics::is_colliding(&queue.queue_name) {
+            continue;
+        }
+        let node_id = build_node_id(&queue.queue_name);
+        get_queue_nodes().insert(node_id.clone(), queue.queue_name.clone());
+
+        let properties = build_queue_properties(queue.markers.iter().map(|marker| marker.name.as_str()));
+        values.push((format!("{node_id}/name"), Some(queue.queue_name.clone())));
+        values.push((format!("{node_id}/description"), Some(queue.description.clone())));
+        values.push((format!("{node_id}/printer-make"), Some(queue.printer_make.clone())));
+        values.push((format!("{node_id}/state"), Some(match queue.state {
+            PrinterState::Idle => "idle",
+            PrinterState::Processing => "processing",
+            PrinterState::Stopped => "stopped",
+        }.to_owned())));
+        // CUPS has no separate paused state, pausing a queue stops it.
+        values.push((format!("{node_id}/{PROPERTY_PAUSED}"), Some((queue.state == PrinterState::Stopped).to_string())));
+        values.push((format!("{node_id}/job-count"), Some(queue.job_count.to_string())));
+        values.push((format!("{node_id}/state-message"), Some(queue.state_message.clone())));
+        values.push((format!("{node_id}/state-reason"), Some(queue.state_reason.clone())));
+
+        for (i, marker) in queue.markers.iter().enumerate() {
+            values.push((format!("{node_id}/marker-{i}-level"), marker.level.map(|level| level.to_string())));
+        }
+
+        nodes.insert(node_id, HomieNodeDescription {
+            name: queue.description.clone(),
+            node_type: "print-queue".to_owned(),
+            properties,
+        });
+    }
+
+    let description = HomieDeviceDescription {
+        homie: String::new(),
+        version: 0,
+        name: format!("CUPS @ {}", topics::get_server_name()),
+        nodes,
+    };
+    let attributes = match settings.mqtt.homie.version {
+        HomieVersion::V4 => build_v4_attributes(&device_topic, &description),
+        HomieVersion::V5 => build_v5_attributes(&device_topic, description)?,
+    };
+
+    // Controllers read the attributes again after the device went through the `init` state.
+    let is_changed = attributes.iter().any(|(topic, payload)| !get_last_published_mqtt_messages().get(topic).is_some_and(|last_published| last_published.payload.eq(payload)));
+    if is_changed {
+        publish_state("init").await?;
+        for (topic, payload) in attributes {
+            publish_with_properties(MessageClass::Discovery, &topic, payload, build_homie_properties(&topic)).await?;
+        }
+    }
+    for (property_topic, value) in values {
+        publish_value(&format!("{device_topic}/{property_topic}"), value).await?;
+    }
+    publish_state("ready").await
+}
+
+/// Marks the device as disconnected, for when CUPS2MQTT stops. The last will marks it as `lost` otherwise.
+pub async fn publish_disconnected() -> Result<(), ApplicationError> {
+    publish_state("disconnected").await
+}
+
+async fn publish_state(state: &str) -> Result<(), ApplicationError> {
+    let topic = get_settings().mqtt.homie.state_topic();
+    publish_status_with_properties(&topic, state.to_owned(), build_homie_properties(&topic)).await
+}
+
+/// Publishes a property value, `None` clears the retained value.
+async fn publish_value(topic: &str, value: Option<String>) -> Result<(), ApplicationError> {
+    let payload = match (get_settings().mqtt.homie.version, value) {
+        (_, None) => String::new(),
+        // Homie 5 can't use an empty message for an empty string, as that clears the retained value.
+        (HomieVersion::V5, Some(value)) if value.is_empty() => "\0".to_owned(),
+        (_, Some(value)) => value,
+    };
+    publish_status_with_properties(topic, payload, build_homie_properties(topic)).await
+}
+
+/// All retained topics of the node of a queue, so they can be cleared when the queue is removed.
+pub fn build_queue_node_topics(queue: &IppPrintQueueState) -> Vec<String> {
+    let settings = get_settings();
+    let device_topic = settings.mqtt.homie.device_topic();
+    let node_id = build_node_id(&queue.queue_name);
+    let properties = build_queue_properties(queue.markers.iter().map(|marker| marker.name.as_str()));
+
+    let mut topics = properties.keys().map(|property_id| format!("{device_topic}/{node_id}/{property_id}")).collect::<Vec<_>>();
+    // Homie 5 only describes nodes in the device description, which is published again without the node.
+    if settings.mqtt.homie.version == HomieVersion::V4 {
+        let node_topic = format!("{device_topic}/{node_id}/");
+        let node = HomieNodeDescription { name: String::new(), node_type: String::new(), properties };
+        let description = HomieDeviceDescription { homie: String::new(), version: 0, name: String::new(), nodes: BTreeMap::from([(node_id, node)]) };
+        topics.extend(build_v4_attributes(&device_topic, &description).into_iter()
+            .map(|(topic, _)| topic)
+            .filter(|topic| topic.starts_with(&node_topic)));
+    }
+    topics
+}
+
+fn build_queue_properties<'a>(marker_names: impl Iterator<Item = &'a str>) -> BTreeMap<String, HomiePropertyDescription> {
+    let mut properties = BTreeMap::from([
+        ("name".to_owned(), build_property("Name", "string")),
+        ("description".to_owned(), build_property("Description", "string")),
+        ("printer-make".to_owned(), build_property("Printer make", "string")),
+        ("state".to_owned(), HomiePropertyDescription { format: Some("idle,processing,stopped".to_owned()), ..build_property("State", "enum") }),
+        (PROPERTY_PAUSED.to_owned(), HomiePropertyDescription { settable: true, ..build_property("Paused", "boolean") }),
+        ("job-count".to_owned(), build_property("Job count", "integer")),
+        ("state-message".to_owned(), build_property("State message", "string")),
+        ("state-reason".to_owned(), build_property("State reason", "string")),
+    ]);
+    for (i, marker_name) in marker_names.enumerate() {
+        properties.insert(format!("marker-{i}-level"), HomiePropertyDescription {
+            format: Some("0:100".to_owned()),
+            unit: Some("%".to_owned()),
+            ..build_property(&format!("{marker_name} level"), "integer")
+        });
+    }
+    properties
+}
+
+fn build_property(name: &str, datatype: &str) -> HomiePropertyDescription {
+    HomiePropertyDescription {
+        name: name.to_owned(),
+        datatype: datatype.to_owned(),
+        format: None,
+        settable: false,
+        unit: None,
+    }
+}
+
+/// Homie 4 publishes every attribute of the device, its nodes and their properties on its own topic.
+fn build_v4_attributes(device_topic: &str, description: &HomieDeviceDescription) -> Vec<(String, String)> {
+    let mut attributes = vec![
+        (format!("{device_topic}/$homie"), "4.0".to_owned()),
+        (format!("{device_topic}/$name"), description.name.clone()),
+        (format!("{device_topic}/$nodes"), description.nodes.keys().cloned().collect::<Vec<_>>().join(",")),
+    ];
+    for (node_id, node) in &description.nodes {
+        let node_topic = format!("{device_topic}/{node_id}");
+        attributes.push((format!("{node_topic}/$name"), node.name.clone()));
+        attributes.push((format!("{node_topic}/$type"), node.node_type.clone()));
+        attributes.push((format!("{node_topic}/$properties"), node.properties.keys().cloned().collect::<Vec<_>>().join(",")));
+        for (property_id, property) in &node.properties {
+            let property_topic = format!("{node_topic}/{property_id}");
+            attributes.push((format!("{property_topic}/$name"), property.name.clone()));
+            attributes.push((format!("{property_topic}/$datatype"), property.datatype.clone()));
+            if let Some(format) = &property.format {
+                attributes.push((format!("{property_topic}/$format"), format.clone()));
+            }
+            if property.settable {
+                attributes.push((format!("{property_topic}/$settable"), "true".to_owned()));
+            }
+            if let Some(unit) = &property.unit {
+                attributes.push((format!("{property_topic}/$unit"), unit.clone()));
+            }
+        }
+    }
+    attributes
+}
+
+/// Homie 5 describes the device in a single JSON document, of which the version has to change whenever it changes.
+fn build_v5_attributes(device_topic: &str, mut description: HomieDeviceDescription) -> Result<Vec<(String, String)>, ApplicationError> {
+    description.homie = "5.0".to_owned();
+    let unversioned = serde_json::to_string(&description).with_whatever_context(|_| "Could not serialize Homie description")?;
+    let mut hasher = DefaultHasher::new();
+    unversioned.hash(&mut hasher);
+    // Keep it within the integers JSON parsers can represent exactly.
+    description.version = hasher.finish() >> 11;
+
+    let payload = serde_json::to_string(&description).with_whatever_context(|_| "Could not serialize Homie description")?;
+    Ok(vec![(format!("{device_topic}/$description"), payload)])
+}
+
+/// Property values and attributes are plain text, except for the Homie 5 description.
+fn build_homie_properties(topic: &str) -> MqttMessageProperties {
+    let mut properties = build_message_properties(None, false);
+    if !topic.ends_with("/$description") {
+        properties.content_type = Some("text/plain".to_owned());
+    }
+    properties
+}
+
+/// Node IDs may only contain lowercase letters, digits and hyphens. Based on the name in `mqtt.queue_topic_names` if
+/// configured, so it can be changed when two queues end up with the same node ID.
+pub fn build_node_id(queue_name: &str) -> String {
+    build_node_id_from_name(get_settings().mqtt.topic_name_for_queue(queue_name).unwrap_or(queue_name))
+}
+
+fn build_node_id_from_name(name: &str) -> String {
+    let node_id = name.to_lowercase()
+        .chars()
+        .map(|c| if c.is_ascii_lowercase() || c.is_ascii_digit() { c } else { '-' })
+        .collect::<String>()
+        .trim_matches('-')
+        .to_owned();
+    match node_id.as_str() {
+        // Without any letter or digit nothing is left, so use the bytes of the name instead.
+        "" => std::iter::once("queue".to_owned()).chain(name.bytes().map(|b| format!("{b:02x}"))).collect::<Vec<_>>().join("-"),
+        // Taken by the node of the server itself.
+        NODE_CUPS_SERVER => format!("queue-{node_id}"),
+        _ => node_id,
+    }
+}
+
+// //////// //
+// Commands //
+// //////// //
+
+/// Topic filter for pausing and resuming any queue.
+pub fn build_paused_subscription() -> String {
+    format!("{}/+/{PROPERTY_PAUSED}/set", get_settings().mqtt.homie.device_topic())
+}
+
+/// Gets the queue name from a `set` message topic of the `paused` property, if it is one.
+pub fn parse_paused_queue_name(topic: &str) -> Option<String> {
+    let node_id = topic.strip_prefix(&format!("{}/", get_settings().mqtt.homie.device_topic()))?
+        .strip_suffix(&format!("/{PROPERTY_PAUSED}/set"))?;
+    get_queue_nodes().get(node_id).map(|queue_name| queue_name.clone())
+}
+
+/// Pauses the queue for `true` and resumes it for `false`.
+pub async fn set_queue_paused(queue_name: &str, payload: &[u8]) -> Result<(), ApplicationError> {
+    let settings = get_settings();
+    let is_paused = match payload {
+        b"true" => true,
+        b"false" => false,
+        _ => whatever!("Paused must be true or false, not {}", String::from_utf8_lossy(payload)),
+    };
+
+    let queue_uri = cups_client::client::build_cups_url(&settings.cups, Some(&queue_name.to_owned())).with_whatever_context(|_| "Could not build CUPS URL")?;
+    match is_paused {
+        true => {
+            cups_client::client::pause_printer(queue_uri, &settings.cups.tls_options()).await.with_whatever_context(|_| format!("Could not pause queue {queue_name}"))?;
+            get_paused_queues().insert(queue_name.to_owned());
+        },
+        false => {
+            cups_client::client::resume_printer(queue_uri, &settings.cups.tls_options()).await.with_whatever_context(|_| format!("Could not resume queue {queue_name}"))?;
+            get_paused_queues().remove(queue_name);
+        },
+    }
+    Ok(())
+}
+
+/// Whether the queue was paused on purpose, through the `paused` property or otherwise, so it shouldn't be resumed or
+/// failed over automatically.
+pub fn is_paused_on_purpose(queue: &IppPrintQueueState) -> bool {
+    if queue.state != PrinterState::Stopped {
+        // Resumed, maybe elsewhere, so when it stops again that isn't on purpose anymore.
+        get_paused_queues().remove(&queue.queue_name);
+        return false;
+    }
+    queue.is_paused() || get_paused_queues().contains(&queue.queue_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_node_id_from_letters_and_digits() {
+        assert_eq!(build_node_id_from_name("office"), "office");
+        assert_eq!(build_node_id_from_name("Photo_Booth 2"), "photo-booth-2");
+        assert_eq!(build_node_id_from_name("_Büro_"), "b-ro");
+    }
+
+    #[test]
+    fn different_names_can_share_node_id() {
+        assert_eq!(build_node_id_from_name("Photo_Booth"), build_node_id_from_name("photo-booth"));
+        assert_eq!(build_node_id_from_name("booth.3"), build_node_id_from_name("BOOTH 3"));
+    }
+
+    #[test]
+    fn never_builds_empty_node_id() {
+        assert_eq!(build_node_id_from_name("%%%"), "queue-25-25-25");
+        assert_eq!(build_node_id_from_name("__"), "queue-5f-5f");
+        assert_eq!(build_node_id_from_name(""), "queue");
+        assert_ne!(build_node_id_from_name("%%%"), build_node_id_from_name("&&&"));
+    }
+
+    #[test]
+    fn never_builds_node_id_of_server() {
+        assert_eq!(build_node_id_from_name("cups-server"), "queue-cups-server");
+        assert_eq!(build_node_id_from_name("CUPS_Server"), "queue-cups-server");
+    }
+}
@@ -0,0 +1,93 @@
+use std::sync::OnceLock;
+
+use dashmap::DashMap;
+use log::{debug, error, info, warn};
+use snafu::ResultExt;
+use tokio::time::sleep;
+
+use crate::{
+    config::models::Settings,
+    cups_client,
+    get_settings,
+    mqtt_client::models::MqttCupsJobStatus,
+    publish,
+    topics::{self, MessageClass},
+    ApplicationError,
+};
+
+/// Jobs submitted through cups2mqtt, keyed by queue name and job ID, with the last published status.
+fn get_tracked_jobs() -> &'static DashMap<(String, i32), Option<MqttCupsJobStatus>> {
+    static TRACKED_JOBS: OnceLock<DashMap<(String, i32), Option<MqttCupsJobStatus>>> = OnceLock::new();
+    TRACKED_JOBS.get_or_init(DashMap::new)
+}
+
+// ////////// //
+// Submitting //
+// ////////// //
+
+/// Prints `job_data` on `queue_name` and starts following the job.
+pub async fn submit_job(queue_name: &str, job_name: String, job_data: Vec<u8>) -> Result<i32, ApplicationError> {
+    let settings = get_settings();
+    let queue_uri = cups_client::client::build_cups_url(&settings.cups, Some(&queue_name.to_owned())).with_whatever_context(|_| "Could not build CUPS URL")?;
+    let job_id = cups_client::client::print_job(queue_uri, &settings.cups.tls_options(), job_name, job_data).await
+        .with_whatever_context(|_| format!("Could not submit job to queue {queue_name}"))?;
+
+    info!("Submitted job {job_id} to queue [{queue_name}]");
+    get_tracked_jobs().insert((queue_name.to_owned(), job_id), None);
+    Ok(job_id)
+}
+
+// //// //
+// Loop //
+// //// //
+
+pub async fn job_tracking_loop(settings: &Settings) {
+    info!("Job tracking loop started");
+    loop {
+        // Collect the keys first, so no DashMap lock is held while waiting for CUPS.
+        let tracked_jobs = get_tracked_jobs().iter().map(|job| job.key().clone()).collect::<Vec<_>>();
+        for (queue_name, job_id) in tracked_jobs {
+            if let Err(e) = update_tracked_job(&queue_name, job_id).await {
+                error!("Failed to update job {job_id} on queue [{queue_name}]: {e}");
+            }
+        }
+
+        let duration = settings.polling_schedule.get_duration_till_next_occurrence().unwrap();
+        sleep(duration).await;
+    }
+}
+
+async fn update_tracked_job(queue_name: &str, job_id: i32) -> Result<(), ApplicationError> {
+    let settings = get_settings();
+    let key = (queue_name.to_owned(), job_id);
+    let queue_uri = cups_client::client::build_cups_url(&settings.cups, Some(&key.0)).with_whatever_context(|_| "Could not build CUPS URL")?;
+
+    let Some(job) = cups_client::client::get_job(queue_uri, &settings.cups.tls_options(), job_id).await.with_whatever_context(|_| "Could not get job from CUPS")? else {
+        warn!("Job {job_id} on queue [{queue_name}] is no longer known by CUPS, stopped following it");
+        get_tracked_jobs().remove(&key);
+        return Ok(());
+    };
+
+    let status = MqttCupsJobStatus::from_ipp_job(queue_name, &job);
+    let previous_state = match get_tracked_jobs().get(&key) {
+        Some(previous_status) if previous_status.as_ref() == Some(&status) => return Ok(()),
+        Some(previous_status) => previous_status.as_ref().map(|s| s.state),
+        None => None,
+    };
+    if previous_state != Some(status.state) {
+        debug!("Job {job_id} on queue [{queue_name}] is now {:?}", status.state);
+    }
+
+    let payload = serde_json::to_string(&status).with_whatever_context(|_| format!("Could not serialize status of job {job_id}"))?;
+    publish(MessageClass::Event, &topics::build_topic(&settings.mqtt.topics.job, Some(queue_name), Some(job_id)), payload.clone()).await?;
+
+    if status.state.is_final() {
+        info!("Job {job_id} on queue [{queue_name}] finished as {:?} ({})", status.state, status.state_reasons.join(", "));
+        publish(MessageClass::Event, &topics::build_topic(&settings.mqtt.topics.job_events, Some(queue_name), None), payload).await?;
+        get_tracked_jobs().remove(&key);
+    } else {
+        get_tracked_jobs().insert(key, Some(status));
+    }
+
+    Ok(())
+}
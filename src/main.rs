@@ -1,50 +1,81 @@
 mod cli;
 
-use std::sync::OnceLock;
+use std::{sync::OnceLock, time::Instant};
 
 use clap::Parser;
-use config::models::Settings;
+use config::models::{PayloadCodec, ProvisioningMode, Settings};
 use backon::{ExponentialBuilder, Retryable};
+use chrono::{DateTime, Utc};
 use convert_case::{Converter, Pattern};
 use cups_client::models::IppPrintQueueState;
 use dashmap::DashMap;
-use log::{debug, error, info};
-use mqtt_client::{client::MqttClient, models::*};
+use log::{debug, error, info, warn};
+use mqtt_client::{client::{MqttClient, MqttError, MqttEvent, MqttLastWill, MqttMessageProperties}, models::*};
 use ron::ser::PrettyConfig;
-use snafu::{OptionExt, ResultExt, Snafu};
+use serde_json::Value;
+use snafu::{whatever, OptionExt, ResultExt, Snafu};
 use url::Url;
-use tokio::{sync::Mutex, task::JoinSet, time::sleep};
+use tokio::{sync::{broadcast::error::RecvError, Mutex}, task::JoinSet, time::sleep};
 
-use crate::{cli::{Cli, Commands}, cups_client::client::{get_raw_print_queues, CupsError}};
+use crate::{cli::{Cli, Commands}, cups_client::client::{get_raw_print_queues, CupsError}, topics::MessageClass};
 
 mod cups_client;
+mod codec;
 mod config;
+mod failover;
+mod homie;
+mod job_tracking;
 mod mqtt_client;
+mod outbox;
+mod provisioning;
+mod queue_cleanup;
+mod recovery;
+mod sparkplug;
+mod stuck_jobs;
+mod templates;
+mod timestamps;
+mod topics;
 
 static PRINT_QUEUES: OnceLock<Mutex<Vec<String>>> = OnceLock::new();
+/// Held during a queue status run, so a refresh or republish doesn't run recovery, failover or provisioning at the
+/// same time as the scheduled run.
+static QUEUE_STATUS_RUN: Mutex<()> = Mutex::const_new(());
 
 pub fn get_settings() -> &'static Settings {
     static LOG_FILE_REGEX: OnceLock<Settings> = OnceLock::new();
-    LOG_FILE_REGEX.get_or_init(|| config::loading::load_config())
+    LOG_FILE_REGEX.get_or_init(config::loading::load_config)
 }
 
+/// Set up by [`init_mqtt_client`] at startup, so configuration errors are reported before anything runs.
+static MQTT_CLIENT: OnceLock<MqttClient> = OnceLock::new();
+
 pub fn get_mqtt_client() -> &'static MqttClient {
-    static LOG_FILE_REGEX: OnceLock<MqttClient> = OnceLock::new();
-    LOG_FILE_REGEX.get_or_init(|| mqtt_client::client::MqttClient::new(&get_settings().mqtt))
+    MQTT_CLIENT.get().expect("MQTT client is set up at startup")
+}
+
+fn init_mqtt_client(settings: &Settings) -> Result<(), MqttError> {
+    let build_last_will = settings.mqtt.sparkplug.enabled.then_some(sparkplug::build_death_certificate as fn() -> MqttLastWill);
+    let mqtt_client = MqttClient::new(&settings.mqtt, build_last_will)?;
+    let _ = MQTT_CLIENT.set(mqtt_client);
+    Ok(())
+}
+
+pub struct LastPublishedMessage {
+    pub payload: String,
+    pub published_at: Instant,
 }
 
-pub fn get_last_published_mqtt_messages() -> &'static DashMap<String, String> {
-    static LOG_FILE_REGEX: OnceLock<DashMap<String, String>> = OnceLock::new();
-    LOG_FILE_REGEX.get_or_init(|| DashMap::new())
+pub fn get_last_published_mqtt_messages() -> &'static DashMap<String, LastPublishedMessage> {
+    static LOG_FILE_REGEX: OnceLock<DashMap<String, LastPublishedMessage>> = OnceLock::new();
+    LOG_FILE_REGEX.get_or_init(DashMap::new)
 }
 
 #[tokio::main(flavor = "multi_thread", worker_threads = 2)]
 async fn main() {
     // As Rust has no native support for .env files,
     // we use the dotenv_flow crate to import to actual ENV vars.
-    let dotenv_path = dotenv_flow::dotenv_flow();
-    if dotenv_path.is_ok() {
-        println!("Loaded dotenv file: {:?}", dotenv_path.unwrap());
+    if let Ok(dotenv_path) = dotenv_flow::dotenv_flow() {
+        println!("Loaded dotenv file: {:?}", dotenv_path);
     }
 
     colog::init();
@@ -62,13 +93,16 @@ async fn failure_wait() {
 }
 
 async fn publish_cups_queue_statuses_and_log_result() -> Result<(), ApplicationError> {
+    let _run_guard = QUEUE_STATUS_RUN.lock().await;
     let settings = get_settings();
     let url = cups_client::client::build_cups_url(&settings.cups, None).with_whatever_context(|_| "Could not build CUPS URL")?;
-    let print_queues_result = cups_client::client::get_print_queues(url, settings.cups.ignore_tls_errors).await;
+    let print_queues_result = cups_client::client::get_print_queues(url, &settings.cups.tls_options()).await;
+    let polled_at = Utc::now();
 
     match &print_queues_result {
         Ok(print_queues) => {
             debug!("Got {} print queue(s)", print_queues.len());
+            topics::update_colliding_queues(print_queues);
 
             // Update the list of print queues used by the supply levels request loop. Start the loop if not already started.
             if settings.cups.report_supply_levels_schedule.is_some() {
@@ -86,13 +120,46 @@ async fn publish_cups_queue_statuses_and_log_result() -> Result<(), ApplicationE
                     debug!("Supply levels queue list updated");
                 }
             }
+
+            match queue_cleanup::remove_disappeared_queues(print_queues).await {
+                Ok(_) => debug!("Checked for disappeared queues"),
+                Err(e) => error!("Failed to remove disappeared queues: {}", e),
+            }
+
+            if settings.stuck_jobs.is_enabled() {
+                match stuck_jobs::publish_stuck_jobs(print_queues).await {
+                    Ok(_) => debug!("Published stuck jobs"),
+                    Err(e) => error!("Failed to publish stuck jobs: {}", e),
+                }
+            }
+
+            if settings.recovery.is_enabled() {
+                match recovery::recover_stopped_queues(print_queues).await {
+                    Ok(_) => debug!("Applied recovery policies"),
+                    Err(e) => error!("Failed to apply recovery policies: {}", e),
+                }
+            }
+
+            if !settings.failover.queues.is_empty() {
+                match failover::fail_over_unhealthy_queues(print_queues).await {
+                    Ok(_) => debug!("Applied failover policies"),
+                    Err(e) => error!("Failed to apply failover policies: {}", e),
+                }
+            }
+
+            if settings.provisioning.mode != ProvisioningMode::Disabled {
+                match provisioning::reconcile_print_queues(print_queues).await {
+                    Ok(_) => debug!("Reconciled print queues"),
+                    Err(e) => error!("Failed to reconcile print queues: {}", e),
+                }
+            }
         },
         Err(e) => {
             error!("Failed to get print queues: {}", e);
         }
     }
 
-    match publish_cups_server_status(&print_queues_result).await {
+    match publish_cups_server_status(&print_queues_result, polled_at).await {
         Ok(_) => {
             debug!("Published server status");
         },
@@ -102,10 +169,24 @@ async fn publish_cups_queue_statuses_and_log_result() -> Result<(), ApplicationE
         }
     }
 
+    if settings.mqtt.homie.enabled {
+        match homie::publish_homie_device(&print_queues_result).await {
+            Ok(_) => debug!("Published Homie device"),
+            Err(e) => error!("Failed to publish Homie device: {}", e),
+        }
+    }
+
+    if settings.mqtt.sparkplug.enabled {
+        match sparkplug::publish_edge_node(&print_queues_result).await {
+            Ok(_) => debug!("Published Sparkplug edge node"),
+            Err(e) => error!("Failed to publish Sparkplug edge node: {}", e),
+        }
+    }
+
     match print_queues_result {
         Ok(print_queues) => {
             // CUPS online, publish print queues.
-            match publish_cups_queue_statuses(&print_queues).await {
+            match publish_cups_queue_statuses(&print_queues, polled_at).await {
                 Ok(()) => {
                     debug!("Published queue statuses");
                     Ok(())
@@ -131,7 +212,7 @@ async fn run_dump() {
     let settings = get_settings();
     let cups_uri = cups_client::client::build_cups_url(&settings.cups, None).unwrap();
 
-    let ipp_result = get_raw_print_queues(cups_uri, settings.cups.ignore_tls_errors).await.unwrap();
+    let ipp_result = get_raw_print_queues(cups_uri, &settings.cups.tls_options()).await.unwrap();
     print!("{}", ron::ser::to_string_pretty(&ipp_result, PrettyConfig::new()).unwrap());
 }
 
@@ -141,10 +222,58 @@ async fn run_service() {
     let settings = get_settings();
     info!("Running with config: {:#?}", settings);
 
+    if let Err(e) = init_mqtt_client(settings) {
+        error!("Invalid MQTT configuration: {e}");
+        std::process::exit(1);
+    }
+    if let Err(e) = recovery::check_settings() {
+        error!("Invalid recovery configuration: {e}");
+        std::process::exit(1);
+    }
+
     let mut set = JoinSet::new();
     set.spawn(print_queue_status_reporting_loop(settings));
+    set.spawn(mqtt_command_loop(settings));
+    set.spawn(job_tracking::job_tracking_loop(settings));
+    if settings.cups.scan_devices_schedule.is_some() {
+        set.spawn(device_scan_loop(settings));
+    }
 
-    while let Some(_cert) = set.join_next().await {};
+    tokio::select! {
+        _ = async { while set.join_next().await.is_some() {} } => {},
+        _ = shutdown_signal() => {
+            info!("Shutting down");
+            set.abort_all();
+            // Without a connection, publishing would wait for it forever. The broker publishes the last will instead.
+            if !get_mqtt_client().is_connected() {
+                return;
+            }
+            if settings.mqtt.homie.enabled && let Err(e) = homie::publish_disconnected().await {
+                error!("Failed to publish Homie device state: {e}");
+            }
+            if settings.mqtt.sparkplug.enabled && let Err(e) = sparkplug::publish_death().await {
+                error!("Failed to publish Sparkplug NDEATH: {e}");
+            }
+            match get_mqtt_client().disconnect().await {
+                Ok(_) => debug!("Disconnected from MQTT"),
+                Err(e) => error!("Failed to disconnect from MQTT: {e}"),
+            }
+        },
+    }
+}
+
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()).expect("Could not listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {},
+            _ = sigterm.recv() => {},
+        }
+    }
+
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
 }
 
 // ///// //
@@ -177,7 +306,7 @@ async fn supply_levels_request_loop(settings: &Settings) {
         for print_queue in PRINT_QUEUES.get().unwrap().lock().await.iter() {
             debug!("Requesting supply levels update for queue [{print_queue}]");
             let print_queue_uri = cups_client::client::build_cups_url(&settings.cups, Some(print_queue)).unwrap();
-            match cups_client::client::report_supply_levels(print_queue_uri, settings.cups.ignore_tls_errors).await {
+            match cups_client::client::report_supply_levels(print_queue_uri, &settings.cups.tls_options()).await {
                 Ok(_) => debug!("Successfully requested supply levels update for queue [{print_queue}]"),
                 Err(error) => error!("Error while requesting support levels update for queue [{print_queue}]: {error}"),
             }
@@ -188,11 +317,134 @@ async fn supply_levels_request_loop(settings: &Settings) {
     }
 }
 
+async fn device_scan_loop(settings: &Settings) {
+    info!("Device scan loop started");
+    loop {
+        debug!("Device scan run started");
+        match publish_cups_devices().await {
+            Ok(_) => debug!("Published devices"),
+            Err(e) => error!("Failed to publish devices: {e}"),
+        }
+
+        let sleep_for = settings.cups.scan_devices_schedule.as_ref().unwrap().get_duration_till_next_occurrence().unwrap();
+        sleep(sleep_for).await;
+    }
+}
+
+async fn mqtt_command_loop(settings: &Settings) {
+    let mqtt_client = get_mqtt_client();
+    let mut events = mqtt_client.events();
+
+    let scan_devices_topic = topics::build_topic(&settings.mqtt.topics.scan_devices_command, None, None);
+    let print_topic = topics::build_subscription(&settings.mqtt.topics.print_command);
+    let refresh_topic = topics::build_topic(&settings.mqtt.topics.refresh_command, None, None);
+    let ha_status_topic = format!("{}/status", settings.mqtt.ha.discovery_topic_prefix);
+    let homie_paused_topic = homie::build_paused_subscription();
+    let sparkplug_command_topic = sparkplug::build_command_topic();
+    let mut topics = vec![&scan_devices_topic, &refresh_topic];
+    if settings.mqtt.print_command.enabled {
+        topics.push(&print_topic);
+    }
+    if settings.mqtt.ha.enable_discovery {
+        topics.push(&ha_status_topic);
+    }
+    if settings.mqtt.homie.enabled {
+        topics.push(&homie_paused_topic);
+    }
+    if settings.mqtt.sparkplug.enabled {
+        topics.push(&sparkplug_command_topic);
+    }
+    for topic in topics {
+        if let Err(e) = mqtt_client.subscribe(topic).await {
+            error!("Failed to subscribe to MQTT commands: {e}");
+            return;
+        }
+    }
+
+    info!("MQTT command loop started");
+    loop {
+        let message = match events.recv().await {
+            Ok(MqttEvent::Message(message)) => message,
+            Ok(MqttEvent::Connected { is_reconnect }) => {
+                // Without persistence, a restarted broker has lost all retained messages.
+                if is_reconnect {
+                    info!("Reconnected to MQTT, republishing everything");
+                    republish_all();
+                } else if settings.mqtt.sparkplug.enabled {
+                    // Sparkplug births are only published while connected, so don't wait for the next run.
+                    refresh_queue_statuses();
+                }
+                if settings.mqtt.outbox.max_messages > 0 {
+                    tokio::spawn(async {
+                        if let Err(e) = outbox::replay().await {
+                            error!("Failed to publish buffered events: {e}");
+                        }
+                    });
+                }
+                continue;
+            },
+            Err(RecvError::Lagged(count)) => {
+                error!("MQTT command loop fell behind, skipped {count} message(s)");
+                continue;
+            },
+            Err(RecvError::Closed) => break,
+        };
+
+        // A retained command would be executed again after every restart or reconnect, so only act on new ones. The
+        // Home Assistant birth message is a state rather than a command, so it may be retained.
+        if message.retain && message.topic != ha_status_topic {
+            warn!("Ignoring retained MQTT command on topic {}, publish commands without the retain flag", message.topic);
+            continue;
+        }
+
+        if message.topic == ha_status_topic {
+            // Home Assistant publishes its birth message after (re)starting, it then needs the discovery messages again.
+            if message.payload == b"online" {
+                info!("Home Assistant came online, republishing everything");
+                republish_all();
+            }
+        } else if message.topic == refresh_topic {
+            info!("Refresh requested through MQTT, republishing everything");
+            republish_all();
+        } else if message.topic == sparkplug_command_topic {
+            if sparkplug::is_rebirth_requested(&message.payload) {
+                info!("Sparkplug rebirth requested");
+                sparkplug::request_rebirth().await;
+                refresh_queue_statuses();
+            }
+        } else if message.topic == scan_devices_topic {
+            info!("Device scan requested through MQTT");
+            // Scanning can take a while, so don't block other commands.
+            tokio::spawn(async {
+                match publish_cups_devices().await {
+                    Ok(_) => debug!("Published devices"),
+                    Err(e) => error!("Failed to publish devices: {e}"),
+                }
+            });
+        } else if let Some(queue_name) = topics::parse_queue_name(&settings.mqtt.topics.print_command, &message.topic).filter(|_| settings.mqtt.print_command.enabled) {
+            info!("Print job for queue [{queue_name}] received through MQTT");
+            // Uploading the document can take a while, so don't block other commands.
+            tokio::spawn(async move {
+                if let Err(e) = job_tracking::submit_job(&queue_name, "CUPS2MQTT job".to_owned(), message.payload).await {
+                    error!("Failed to submit print job: {e}");
+                }
+            });
+        } else if let Some(queue_name) = homie::parse_paused_queue_name(&message.topic) {
+            info!("Pausing or resuming queue [{queue_name}] requested through Homie");
+            match homie::set_queue_paused(&queue_name, &message.payload).await {
+                // Publish the new state right away instead of on the next run.
+                Ok(_) => refresh_queue_statuses(),
+                Err(e) => error!("Failed to pause or resume queue [{queue_name}]: {e}"),
+            }
+        }
+    }
+}
+
 // //////////////////// //
 // Print server publish //
 // //////////////////// //
 
-async fn publish_cups_server_status(print_queues_result: &Result<Vec<IppPrintQueueState>, CupsError>) -> Result<(), ApplicationError> {
+async fn publish_cups_server_status(print_queues_result: &Result<Vec<IppPrintQueueState>, CupsError>, polled_at: DateTime<Utc>) -> Result<(), ApplicationError> {
     let settings = get_settings();
 
     let cups_version = match print_queues_result {
@@ -200,13 +452,25 @@ async fn publish_cups_server_status(print_queues_result: &Result<Vec<IppPrintQue
         Err(_) => None,
     };
 
-    let topic = format!("{}/{}", settings.mqtt.root_topic, "cups_server");
-    let payload = serde_json::to_string(&MqttCupsServerStatus {
+    let topic = topics::build_topic(&settings.mqtt.topics.server_status, None, None);
+    let mut status = MqttCupsServerStatus {
         is_reachable: print_queues_result.is_ok(),
         cups_version: cups_version.clone(),
         cups2mqtt_version: env!("CARGO_PKG_VERSION").to_owned(),
-    }).with_whatever_context(|_| format!("Could not serialize CUPS server status message for topic {topic}"))?;
-    publish(&topic, payload).await?;
+        timestamps: None,
+    };
+    let content = serde_json::to_string(&status).with_whatever_context(|_| format!("Could not serialize CUPS server status message for topic {topic}"))?;
+    status.timestamps = Some(timestamps::build_timestamps(&topic, &content, &status.is_reachable.to_string(), polled_at));
+    match templates::render_server_status(&status, print_queues_result)? {
+        Some(payload) => publish_templated_status(&topic, payload, None).await?,
+        None => {
+            let payload = serde_json::to_string(&status).with_whatever_context(|_| format!("Could not serialize CUPS server status message for topic {topic}"))?;
+            publish_status(&topic, payload, None).await?;
+        },
+    }
+    if settings.mqtt.publish_polled_at {
+        publish_polled_at(&topic, polled_at, None).await?;
+    }
 
     if settings.mqtt.ha.enable_discovery {
         publish_ha_bridge_discovery_topic(&cups_version, "cups_version", "CUPS version").await?;
@@ -225,9 +489,11 @@ async fn publish_ha_bridge_discovery_topic(cups_version: &Option<String>, integr
     let topic = format!("{}/sensor/{}_cups_server/{}/config", settings.mqtt.ha.discovery_topic_prefix, settings.mqtt.ha.component_id, integration_name);
     let payload = serde_json::to_string(&HomeAssistantDiscoverySensorPayload {
         name: sensor_name.to_owned(),
-        state_topic: format!("{}/cups_server", settings.mqtt.root_topic),
+        state_topic: topics::build_topic(&settings.mqtt.topics.server_status, None, None),
         unique_id: format!("cups_server_{}_{}", integration_name, settings.mqtt.ha.component_id),
         value_template: format!("{{{{ value_json.{} }}}}", integration_name),
+        expire_after: settings.mqtt.ha.expire_after.as_ref().map(|expire_after| expire_after.0.as_secs()),
+        availability: build_ha_availability(),
         device: HomeAssistantDevice {
             identifiers: vec![format!("{}_cups_server", settings.mqtt.ha.component_id)],
             name: format!("CUPS @ {}", display_url),
@@ -236,34 +502,62 @@ async fn publish_ha_bridge_discovery_topic(cups_version: &Option<String>, integr
             via_device: None,
         },
     }).with_whatever_context(|_| format!("Could not serialize HA bridge discovery message for topic {topic}"))?;
-    Ok(publish(&topic, payload).await?)
+    publish(MessageClass::Discovery, &topic, payload).await
+}
+
+async fn publish_cups_devices() -> Result<(), ApplicationError> {
+    let settings = get_settings();
+    let url = cups_client::client::build_cups_url(&settings.cups, None).with_whatever_context(|_| "Could not build CUPS URL")?;
+    let devices = cups_client::client::get_devices(url, &settings.cups.tls_options()).await.with_whatever_context(|_| "Could not get devices from CUPS")?;
+    debug!("Got {} device(s)", devices.len());
+
+    let topic = topics::build_topic(&settings.mqtt.topics.devices, None, None);
+    let payload = serde_json::to_string(&MqttCupsDevices {
+        devices: devices.iter().map(MqttCupsDevice::from).collect(),
+    }).with_whatever_context(|_| format!("Could not serialize CUPS devices message for topic {topic}"))?;
+    publish(MessageClass::CommandResponse, &topic, payload).await
 }
 
 // /////////////////// //
 // Print queue publish //
 // /////////////////// //
 
-async fn publish_cups_queue_statuses(print_queues: &Vec<IppPrintQueueState>) -> Result<(), ApplicationError> {
+async fn publish_cups_queue_statuses(print_queues: &Vec<IppPrintQueueState>, polled_at: DateTime<Utc>) -> Result<(), ApplicationError> {
     let settings = get_settings();
 
     for queue in print_queues {
+        if topics::is_colliding(&queue.queue_name) {
+            continue;
+        }
         let queue_name = queue.queue_name.clone();
 
-        let topic = format!("{}/{}", settings.mqtt.root_topic, queue_name);
-        let payload = serde_json::to_string(&MqttCupsPrintQueueStatus::from(queue)).with_whatever_context(|_| format!("Could not serialize CUPS queue status message for topic {topic}"))?;
-        publish(&topic, payload).await?;
+        let topic = topics::build_topic(&settings.mqtt.topics.queue_status, Some(&queue_name), None);
+        let mut status = MqttCupsPrintQueueStatus::from(queue);
+        let content = serde_json::to_string(&status).with_whatever_context(|_| format!("Could not serialize CUPS queue status message for topic {topic}"))?;
+        status.timestamps = Some(timestamps::build_timestamps(&topic, &content, &format!("{:?}", status.state), polled_at));
+        match templates::render_queue_status(queue, &status)? {
+            Some(payload) => publish_templated_status(&topic, payload, Some(&queue_name)).await?,
+            None => {
+                let payload = serde_json::to_string(&status).with_whatever_context(|_| format!("Could not serialize CUPS queue status message for topic {topic}"))?;
+                publish_status(&topic, payload, Some(&queue_name)).await?;
+            },
+        }
+        if settings.mqtt.flat_topics {
+            publish_flat_queue_status(&topic, &status).await?;
+        }
+        // The flat topics already have it when it's part of the status.
+        if settings.mqtt.publish_polled_at && !(settings.mqtt.flat_topics && status.timestamps.as_ref().is_some_and(|t| t.polled_at.is_some())) {
+            publish_polled_at(&topic, polled_at, Some(&queue_name)).await?;
+        }
 
         if settings.mqtt.ha.enable_discovery {
-            publish_ha_sensor_discovery_topic(&queue, "name", None, None).await?;
-            publish_ha_sensor_discovery_topic(&queue, "description", None, None).await?;
-            publish_ha_sensor_discovery_topic(&queue, "state", None, None).await?;
-            publish_ha_sensor_discovery_topic(&queue, "job_count", None, None).await?;
-            publish_ha_sensor_discovery_topic(&queue, "state_message", None, None).await?;
-            publish_ha_sensor_discovery_topic(&queue, "state_reason", None, None).await?;
+            for field in HA_QUEUE_SENSOR_FIELDS {
+                publish_ha_sensor_discovery_topic(queue, field, None, None).await?;
+            }
             for (i, marker) in queue.markers.iter().enumerate() {
-                publish_ha_sensor_discovery_topic(&queue, &format!("markers[{i}].type"), Some(&format!("marker{i}_type")), Some(&format!("{} type", marker.name))).await?;
-                publish_ha_sensor_discovery_topic(&queue, &format!("markers[{i}].color"), Some(&format!("marker{i}_color")), Some(&format!("{} color", marker.name))).await?;
-                publish_ha_sensor_discovery_topic(&queue, &format!("markers[{i}].level"), Some(&format!("marker{i}_level")), Some(&format!("{} level", marker.name))).await?;
+                for field in HA_MARKER_SENSOR_FIELDS {
+                    publish_ha_sensor_discovery_topic(queue, &format!("markers[{i}].{field}"), Some(&build_ha_marker_object_id(i, field)), Some(&format!("{} {field}", marker.name))).await?;
+                }
             }
         }
     }
@@ -271,38 +565,220 @@ async fn publish_cups_queue_statuses(print_queues: &Vec<IppPrintQueueState>) ->
     Ok(())
 }
 
+/// Publishes every field of the queue status as plain text on `<topic>/<field>`, and every marker field on
+/// `<topic>/markers/<marker name>/<field>`. Missing values are published as empty message, clearing the retained one.
+async fn publish_flat_queue_status(topic: &str, status: &MqttCupsPrintQueueStatus) -> Result<(), ApplicationError> {
+    for (field_topic, payload) in build_flat_queue_status(topic, status)? {
+        publish_flat_status(&field_topic, payload, &status.name).await?;
+    }
+    Ok(())
+}
+
+/// Splits the queue status into a topic and plain text payload per field.
+pub fn build_flat_queue_status(topic: &str, status: &MqttCupsPrintQueueStatus) -> Result<Vec<(String, String)>, ApplicationError> {
+    let fields = serde_json::to_value(status).with_whatever_context(|_| format!("Could not serialize CUPS queue status fields for topic {topic}"))?;
+    let Value::Object(fields) = fields else {
+        whatever!("CUPS queue status for topic {topic} is not an object");
+    };
+
+    let mut flat_fields = Vec::<(String, String)>::new();
+    for (field, value) in fields {
+        match value {
+            Value::Array(markers) if field == "markers" => {
+                for marker in markers {
+                    let Value::Object(mut marker_fields) = marker else { continue };
+                    let Some(Value::String(marker_name)) = marker_fields.remove("name") else { continue };
+                    // Escaped like queue names, as slashes and wildcards would end up as extra levels or break subscriptions.
+                    let marker_name = topics::escape_topic_level(&marker_name);
+                    for (marker_field, marker_value) in marker_fields {
+                        flat_fields.push((format!("{topic}/markers/{marker_name}/{marker_field}"), flat_value(marker_value)));
+                    }
+                }
+            },
+            value => flat_fields.push((format!("{topic}/{field}"), flat_value(value))),
+        }
+    }
+
+    Ok(flat_fields)
+}
+
+async fn publish_flat_status(topic: &str, payload: String, queue_name: &str) -> Result<(), ApplicationError> {
+    let mut properties = build_message_properties(Some(queue_name), true);
+    properties.content_type = Some("text/plain".to_owned());
+    publish_status_with_properties(topic, payload, properties).await
+}
+
+/// Topic on which is published when CUPS was polled for the status on `status_topic`.
+pub fn build_polled_at_topic(status_topic: &str) -> String {
+    format!("{status_topic}/polled_at")
+}
+
+/// Publishes when CUPS was polled apart from the status, so the status itself is only published when it changed.
+async fn publish_polled_at(status_topic: &str, polled_at: DateTime<Utc>, queue_name: Option<&str>) -> Result<(), ApplicationError> {
+    let mut properties = build_message_properties(queue_name, true);
+    properties.content_type = Some("text/plain".to_owned());
+    publish_status_with_properties(&build_polled_at_topic(status_topic), polled_at.to_rfc3339(), properties).await
+}
+
+/// Strings are published without quotes and nulls as empty string, everything else as JSON.
+fn flat_value(value: Value) -> String {
+    match value {
+        Value::String(value) => value,
+        Value::Null => String::new(),
+        value => value.to_string(),
+    }
+}
+
+/// Fields of a queue status with a Home Assistant sensor, besides those of its markers.
+pub const HA_QUEUE_SENSOR_FIELDS: [&str; 6] = ["name", "description", "state", "job_count", "state_message", "state_reason"];
+/// Fields of every marker with a Home Assistant sensor.
+pub const HA_MARKER_SENSOR_FIELDS: [&str; 3] = ["type", "color", "level"];
+
+pub fn build_ha_marker_object_id(index: usize, field: &str) -> String {
+    format!("marker{index}_{field}")
+}
+
 async fn publish_ha_sensor_discovery_topic(queue: &IppPrintQueueState, integration_name: &str, topic_name_override: Option<&str>, name_override: Option<&str>) -> Result<(), ApplicationError> {
     let settings = get_settings();
     let case_converter = Converter::new().set_pattern(Pattern::Sentence).set_delimiter(" ");
     let sensor_topic = topic_name_override.unwrap_or(integration_name);
 
-    let topic = format!("{}/sensor/{}_{}/{}/config", settings.mqtt.ha.discovery_topic_prefix, settings.mqtt.ha.component_id, queue.queue_name, sensor_topic);
+    let queue_slug = topics::get_queue_slug(&queue.queue_name);
+    let topic = topics::build_ha_queue_discovery_topic("sensor", &queue.queue_name, sensor_topic);
     let payload = serde_json::to_string(&HomeAssistantDiscoverySensorPayload {
-        name: name_override.unwrap_or(&case_converter.convert(integration_name.to_owned())).to_owned(),
-        state_topic: format!("{}/{}", settings.mqtt.root_topic, queue.queue_name),
-        unique_id: format!("{}_{}_{}", queue.queue_name, sensor_topic, settings.mqtt.ha.component_id),
+        name: name_override.unwrap_or(&case_converter.convert(integration_name)).to_owned(),
+        state_topic: topics::build_topic(&settings.mqtt.topics.queue_status, Some(&queue.queue_name), None),
+        unique_id: format!("{}_{}_{}", queue_slug, sensor_topic, settings.mqtt.ha.component_id),
         value_template: format!("{{{{ value_json.{} }}}}", integration_name),
-        device: HomeAssistantDevice {
-            identifiers: vec![format!("{}_{}", settings.mqtt.ha.component_id, queue.queue_name.to_owned())],
-            name: queue.description.to_owned(),
-            model: queue.printer_make.to_owned(),
-            sw_version: None,
-            via_device: Some(format!("{}_cups_server", settings.mqtt.ha.component_id)),
-        },
+        expire_after: settings.mqtt.ha.expire_after.as_ref().map(|expire_after| expire_after.0.as_secs()),
+        availability: build_ha_availability(),
+        device: build_ha_queue_device(queue),
     }).with_whatever_context(|_| format!("Could not serialize HA device discovery message for topic {topic}"))?;
-    Ok(publish(&topic, payload).await?)
+    publish(MessageClass::Discovery, &topic, payload).await
+}
+
+/// The topic which tells whether cups2mqtt is running, backed by the last will.
+fn build_ha_availability() -> Vec<HomeAssistantAvailability> {
+    let mqtt_settings = &get_settings().mqtt;
+    match mqtt_settings.availability_topic() {
+        Some(topic) => vec![HomeAssistantAvailability { topic, ..Default::default() }],
+        // The Homie state is `lost` when the last will was published, and `disconnected` when cups2mqtt stopped.
+        None if !mqtt_settings.sparkplug.enabled => vec![HomeAssistantAvailability {
+            topic: mqtt_settings.homie.state_topic(),
+            payload_available: Some("ready".to_owned()),
+            payload_not_available: Some("lost".to_owned()),
+            value_template: Some("{{ 'lost' if value in ['lost', 'disconnected'] else 'ready' }}".to_owned()),
+        }],
+        // The Sparkplug NDEATH is the last will, which Home Assistant can't read.
+        None => Vec::new(),
+    }
+}
+
+fn build_ha_queue_device(queue: &IppPrintQueueState) -> HomeAssistantDevice {
+    let settings = get_settings();
+    HomeAssistantDevice {
+        identifiers: vec![format!("{}_{}", settings.mqtt.ha.component_id, topics::get_queue_slug(&queue.queue_name))],
+        name: queue.description.to_owned(),
+        model: queue.printer_make.to_owned(),
+        sw_version: None,
+        via_device: Some(format!("{}_cups_server", settings.mqtt.ha.component_id)),
+    }
 }
 
 // /////// //
 // Helpers //
 // /////// //
 
-async fn publish(topic: &str, payload: String) -> Result<(), ApplicationError> {
-    let last_published = get_last_published_mqtt_messages().get(topic);
-    Ok(if last_published.is_none() || !last_published.with_whatever_context(|| "Failed to get last published message")?.eq(&payload) {
-        get_last_published_mqtt_messages().insert(topic.to_owned(), payload.clone());
-        get_mqtt_client().publish(topic, payload.as_bytes()).await.with_whatever_context(|_| "Could not publish to MQTT")?;
-    })
+/// Version of the JSON payloads, sent as MQTT v5 user property so consumers can detect breaking changes.
+const PAYLOAD_SCHEMA_VERSION: &str = "1";
+
+/// Publishes a message with the QoS and retain flag configured for its `class`. Except for events, unchanged messages
+/// aren't published again.
+async fn publish(class: MessageClass, topic: &str, payload: String) -> Result<(), ApplicationError> {
+    publish_with_properties(class, topic, payload, build_message_properties(None, false)).await
+}
+
+/// Publishes the status of the print server or (when `queue_name` is set) a print queue. With MQTT v5 these expire after
+/// the configured message expiry, so stale state disappears when CUPS2MQTT stops updating it.
+async fn publish_status(topic: &str, payload: String, queue_name: Option<&str>) -> Result<(), ApplicationError> {
+    publish_status_with_properties(topic, payload, build_message_properties(queue_name, true)).await
+}
+
+/// Publishes a status rendered from a user-defined template, which isn't necessarily JSON.
+async fn publish_templated_status(topic: &str, payload: String, queue_name: Option<&str>) -> Result<(), ApplicationError> {
+    let mut properties = build_message_properties(queue_name, true);
+    properties.content_type = None;
+    publish_status_with_properties(topic, payload, properties).await
+}
+
+/// Whether the status is published on every run, even when unchanged, to keep it alive: expiring messages, and the status
+/// of sensors which Home Assistant makes unavailable when they aren't updated.
+pub fn is_status_republished() -> bool {
+    let settings = get_settings();
+    settings.mqtt.message_expiry.is_some() || (settings.mqtt.ha.enable_discovery && settings.mqtt.ha.expire_after.is_some())
+}
+
+async fn publish_status_with_properties(topic: &str, payload: String, properties: MqttMessageProperties) -> Result<(), ApplicationError> {
+    if is_status_republished() {
+        get_last_published_mqtt_messages().remove(topic);
+    }
+    publish_with_properties(MessageClass::Status, topic, payload, properties).await
+}
+
+async fn publish_with_properties(class: MessageClass, topic: &str, payload: String, mut properties: MqttMessageProperties) -> Result<(), ApplicationError> {
+    // Events are gone when missed, so keep them until the broker can be reached.
+    if class == MessageClass::Event && outbox::should_buffer() {
+        outbox::push(topic, payload, properties);
+        return Ok(());
+    }
+    if class.is_deduplicated() {
+        // Unchanged messages are published again after their max age, for consumers which missed them.
+        let max_age = topics::get_max_age(class, topic);
+        let is_unchanged = get_last_published_mqtt_messages().get(topic).is_some_and(|last_published| {
+            last_published.payload.eq(&payload) && max_age.is_none_or(|max_age| last_published.published_at.elapsed() < max_age)
+        });
+        if is_unchanged {
+            return Ok(());
+        }
+        get_last_published_mqtt_messages().insert(topic.to_owned(), LastPublishedMessage { payload: payload.clone(), published_at: Instant::now() });
+    }
+    let policy = class.policy();
+    let payload = codec::encode_payload(class, payload, &mut properties)?;
+    get_mqtt_client().publish(topic, &payload, policy.qos, policy.retain, &properties).await.with_whatever_context(|_| "Could not publish to MQTT")
+}
+
+/// Builds the MQTT v5 properties for a JSON payload. Only status payloads expire, as discovery and other retained
+/// messages are not republished periodically when unchanged.
+fn build_message_properties(queue_name: Option<&str>, is_status: bool) -> MqttMessageProperties {
+    let settings = get_settings();
+
+    let mut user_properties = vec![("server".to_owned(), topics::get_server_name().to_owned())];
+    if let Some(queue_name) = queue_name {
+        user_properties.push(("queue".to_owned(), queue_name.to_owned()));
+    }
+    user_properties.push(("schema_version".to_owned(), PAYLOAD_SCHEMA_VERSION.to_owned()));
+
+    MqttMessageProperties {
+        content_type: Some(PayloadCodec::Json.content_type().to_owned()),
+        message_expiry: settings.mqtt.message_expiry.as_ref().filter(|_| is_status).map(|expiry| expiry.0),
+        user_properties,
+    }
+}
+
+/// Forgets which messages have been published and runs the queue status report, so all state and discovery messages get
+/// published again. Other messages are published again on their next run.
+fn republish_all() {
+    get_last_published_mqtt_messages().clear();
+    refresh_queue_statuses();
+}
+
+/// Runs the queue status report in the background once any run in progress is done, publishing whatever changed.
+fn refresh_queue_statuses() {
+    tokio::spawn(async {
+        if let Err(e) = publish_cups_queue_statuses_and_log_result().await {
+            error!("Failed to publish queue statuses: {e}");
+        }
+    });
 }
 
 // ////// //
@@ -318,3 +794,76 @@ pub enum ApplicationError {
         source: Option<Box<dyn std::error::Error + Send + Sync>>,
     },
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::mqtt_client::models::{MqttCupsPrinterMarker, MqttCupsPrinterState, MqttTimestamps};
+
+    fn build_queue_status(timestamps: Option<MqttTimestamps>) -> MqttCupsPrintQueueStatus {
+        MqttCupsPrintQueueStatus {
+            name: "dnp_left".to_owned(),
+            description: "DNP \"left\"".to_owned(),
+            printer_make: "DNP DS620".to_owned(),
+            state: MqttCupsPrinterState::Stopped,
+            job_count: 2,
+            state_message: String::new(),
+            state_reason: "media-empty-error".to_owned(),
+            markers: vec![
+                MqttCupsPrinterMarker { marker_type: "ribbon".to_owned(), color: None, name: "Ribbon".to_owned(), level: Some(42) },
+                MqttCupsPrinterMarker { marker_type: "media".to_owned(), color: Some("#FFFFFF".to_owned()), name: "Paper 6/4".to_owned(), level: None },
+            ],
+            timestamps,
+        }
+    }
+
+    fn build_flat_fields(status: &MqttCupsPrintQueueStatus) -> BTreeMap<String, String> {
+        build_flat_queue_status("cups/dnp_left", status).unwrap().into_iter().collect()
+    }
+
+    #[test]
+    fn flattens_queue_status() {
+        let expected = [
+            ("cups/dnp_left/name", "dnp_left"),
+            ("cups/dnp_left/description", "DNP \"left\""),
+            ("cups/dnp_left/printer_make", "DNP DS620"),
+            ("cups/dnp_left/state", "Stopped"),
+            ("cups/dnp_left/job_count", "2"),
+            ("cups/dnp_left/state_message", ""),
+            ("cups/dnp_left/state_reason", "media-empty-error"),
+            ("cups/dnp_left/markers/Ribbon/type", "ribbon"),
+            ("cups/dnp_left/markers/Ribbon/color", ""),
+            ("cups/dnp_left/markers/Ribbon/level", "42"),
+            ("cups/dnp_left/markers/Paper 6%2F4/type", "media"),
+            ("cups/dnp_left/markers/Paper 6%2F4/color", "#FFFFFF"),
+            ("cups/dnp_left/markers/Paper 6%2F4/level", ""),
+        ].map(|(topic, value)| (topic.to_owned(), value.to_owned()));
+
+        assert_eq!(build_flat_fields(&build_queue_status(None)), BTreeMap::from(expected));
+    }
+
+    #[test]
+    fn flattens_timestamps() {
+        let timestamps = MqttTimestamps {
+            last_updated: "2026-10-18T10:00:00+00:00".to_owned(),
+            last_changed: "2026-10-18T09:00:00+00:00".to_owned(),
+            polled_at: Some("2026-10-18T10:00:05+00:00".to_owned()),
+        };
+        let flat_fields = build_flat_fields(&build_queue_status(Some(timestamps)));
+
+        assert_eq!(flat_fields["cups/dnp_left/last_updated"], "2026-10-18T10:00:00+00:00");
+        assert_eq!(flat_fields["cups/dnp_left/last_changed"], "2026-10-18T09:00:00+00:00");
+        assert_eq!(flat_fields["cups/dnp_left/polled_at"], "2026-10-18T10:00:05+00:00");
+    }
+
+    #[test]
+    fn leaves_out_unset_polled_at() {
+        let timestamps = MqttTimestamps { last_updated: String::new(), last_changed: String::new(), polled_at: None };
+        let flat_fields = build_flat_fields(&build_queue_status(Some(timestamps)));
+
+        assert!(flat_fields.contains_key("cups/dnp_left/last_updated"));
+        assert!(!flat_fields.contains_key("cups/dnp_left/polled_at"));
+    }
+}
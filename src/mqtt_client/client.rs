@@ -1,62 +1,423 @@
 use backon::{ExponentialBuilder, RetryableWithContext};
-use log::error;
-use rumqttc::{tokio_rustls::rustls::ClientConfig, AsyncClient, EventLoop, MqttOptions, QoS};
+use http::{HeaderName, HeaderValue};
+use log::{debug, error, warn};
+use rumqttc::{v5, AsyncClient, Event, EventLoop, LastWill, MqttOptions, Outgoing, Packet, QoS, Transport};
+use serde::{Deserialize, Serialize};
 use snafu::{ResultExt, Snafu};
-use tokio::task;
-use std::{sync::Arc, time::Duration};
+use tokio::{sync::{broadcast, Notify}, task, time::timeout};
+use std::{sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex}, time::Duration};
 
-use crate::config::models::Mqtt;
+use crate::config::models::{Mqtt, MqttProtocolVersion, MqttQos, MqttTransport};
 
-use super::fun_with_tls::{get_system_certs, NoopServerCertVerifier};
+use super::fun_with_tls::build_client_config;
+
+const AVAILABILITY_ONLINE: &str = "online";
+const AVAILABILITY_OFFLINE: &str = "offline";
+const HOMIE_STATE_LOST: &str = "lost";
 
 pub struct MqttClient {
-    client: AsyncClient,
+    client: ProtocolClient,
+    availability_topic: Option<String>,
+    is_connected: Arc<AtomicBool>,
+    disconnected: Arc<Notify>,
+    subscriptions: Arc<Mutex<Vec<String>>>,
+    events: broadcast::Sender<MqttEvent>,
+}
+
+#[derive(Debug, Clone)]
+pub enum MqttEvent {
+    /// A (new) connection to the broker has been made. The broker might have been restarted, so
+    /// any (retained) messages published before may be gone.
+    Connected {
+        /// Whether there was a connection before this one. Tracked by the event loop, so it's also right for receivers
+        /// which subscribed after the first connection was made.
+        is_reconnect: bool,
+    },
+    Message(MqttIncomingMessage),
+}
+
+#[derive(Debug, Clone)]
+pub struct MqttIncomingMessage {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    /// Whether the broker delivered a message retained before we subscribed, instead of a newly published one.
+    pub retain: bool,
+}
+
+/// A last will, built again before every connection attempt, as it may have to differ per connection.
+#[derive(Debug, Clone)]
+pub struct MqttLastWill {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub retain: bool,
+}
+
+/// Metadata to publish a message with. Only sent when using MQTT v5, ignored otherwise.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MqttMessageProperties {
+    pub content_type: Option<String>,
+    pub message_expiry: Option<Duration>,
+    pub user_properties: Vec<(String, String)>,
 }
 
 impl MqttClient {
-    pub fn new(mqtt_settings: &Mqtt) -> Self {
-        let mqtt_options = MqttOptions::new(mqtt_settings.client_id.to_owned(), mqtt_settings.host.to_owned(), mqtt_settings.port)
-            .set_credentials(mqtt_settings.username.to_owned(), mqtt_settings.password.to_owned())
-            .set_transport(match mqtt_settings.secure {
-                true => {
-                    let config: ClientConfig = match mqtt_settings.ignore_tls_errors {
-                        // TLS without certificate verification.
-                        true => ClientConfig::builder().dangerous().with_custom_certificate_verifier(Arc::new(NoopServerCertVerifier {})).with_no_client_auth(),
-                        // TLS with certificate verification.
-                        false => ClientConfig::builder().with_root_certificates(get_system_certs().clone()).with_no_client_auth(),
-                    };
-                    rumqttc::Transport::tls_with_config(rumqttc::TlsConfiguration::Rustls(Arc::new(config)))
+    /// Connects with `build_last_will` as last will if given, like the Sparkplug NDEATH, or with the Homie state or
+    /// availability otherwise.
+    pub fn new(mqtt_settings: &Mqtt, build_last_will: Option<fn() -> MqttLastWill>) -> Result<Self, MqttError> {
+        let availability_topic = mqtt_settings.availability_topic();
+        // There can only be one last will, Homie controllers need to know when the device is gone.
+        let (last_will_topic, last_will_payload) = match &availability_topic {
+            Some(availability_topic) => (availability_topic.clone(), AVAILABILITY_OFFLINE),
+            None => (mqtt_settings.homie.state_topic(), HOMIE_STATE_LOST),
+        };
+        let default_last_will = MqttLastWill { topic: last_will_topic, payload: last_will_payload.as_bytes().to_vec(), retain: true };
+        let build_last_will: Arc<dyn Fn() -> MqttLastWill + Send + Sync> = match build_last_will {
+            Some(build_last_will) => Arc::new(build_last_will),
+            None => Arc::new(move || default_last_will.clone()),
+        };
+        let is_connected = Arc::new(AtomicBool::new(false));
+        let disconnected = Arc::new(Notify::new());
+        let subscriptions = Arc::new(Mutex::new(Vec::<String>::new()));
+        let events = broadcast::channel(32).0;
+        let build_handler = |client: &ProtocolClient| EventLoopHandler {
+            client: client.clone(),
+            availability_topic: availability_topic.clone(),
+            is_connected: is_connected.clone(),
+            disconnected: disconnected.clone(),
+            subscriptions: subscriptions.clone(),
+            events: events.clone(),
+            build_last_will: build_last_will.clone(),
+            has_connected: false,
+        };
+
+        let client = match mqtt_settings.protocol_version {
+            MqttProtocolVersion::V311 => {
+                let mut mqtt_options = MqttOptions::new(mqtt_settings.client_id.to_owned(), build_broker_address(mqtt_settings), mqtt_settings.port)
+                    .set_credentials(mqtt_settings.username.to_owned(), mqtt_settings.password.to_owned())
+                    .set_transport(build_transport(mqtt_settings)?)
+                    .set_keep_alive(Duration::from_secs(10)).to_owned();
+                if mqtt_settings.transport == MqttTransport::WebSocket {
+                    let headers = build_websocket_headers(mqtt_settings)?;
+                    mqtt_options.set_request_modifier(move |request| add_websocket_headers(request, headers.clone()));
                 }
-                // No TLS.
-                false => rumqttc::Transport::tcp(),
-            })
-            .set_keep_alive(Duration::from_secs(10)).to_owned();
+                let (client, eventloop) = AsyncClient::new(mqtt_options, 10);
+                let client = ProtocolClient::V311(client);
+                task::spawn(run_v311_event_loop(eventloop, build_handler(&client)));
+                client
+            },
+            MqttProtocolVersion::V5 => {
+                let mut mqtt_options = v5::MqttOptions::new(mqtt_settings.client_id.to_owned(), build_broker_address(mqtt_settings), mqtt_settings.port)
+                    .set_credentials(mqtt_settings.username.to_owned(), mqtt_settings.password.to_owned())
+                    .set_transport(build_transport(mqtt_settings)?)
+                    .set_keep_alive(Duration::from_secs(10)).to_owned();
+                if mqtt_settings.transport == MqttTransport::WebSocket {
+                    let headers = build_websocket_headers(mqtt_settings)?;
+                    mqtt_options.set_request_modifier(move |request| add_websocket_headers(request, headers.clone()));
+                }
+                let (client, eventloop) = v5::AsyncClient::new(mqtt_options, 10);
+                let client = ProtocolClient::V5(client);
+                task::spawn(run_v5_event_loop(eventloop, build_handler(&client)));
+                client
+            },
+        };
+
+        Ok(Self { client, availability_topic, is_connected, disconnected, subscriptions, events })
+    }
+
+    /// Publishes `offline` to the availability topic, if any, and disconnects, so the last will isn't needed. Without a
+    /// connection there's nothing to do, the broker publishes the last will itself.
+    pub async fn disconnect(&self) -> Result<(), MqttError> {
+        if !self.is_connected() {
+            return Ok(());
+        }
+        // Requests wait for room in the request queue, which isn't emptied when the connection drops meanwhile.
+        timeout(Duration::from_secs(5), async {
+            if let Some(availability_topic) = &self.availability_topic {
+                self.client.publish(availability_topic, AVAILABILITY_OFFLINE.as_bytes(), MqttQos::AtLeastOnce, true, &availability_properties()).await.with_whatever_context(|_| "Could not publish availability")?;
+            }
+            self.client.disconnect().await?;
+            self.disconnected.notified().await;
+            Ok(())
+        }).await.with_whatever_context(|_| "Timed out while disconnecting")?
+    }
+
+    pub async fn publish(&self, topic: &str, payload: &[u8], qos: MqttQos, retain: bool, properties: &MqttMessageProperties) -> Result<(), MqttError> {
+        self.client.publish(topic, payload, qos, retain, properties).await.with_whatever_context(|_| format!("Could not publish to topic {topic}"))
+    }
+
+    /// Subscribes to `topic`, the subscription is restored automatically after reconnecting.
+    /// Received messages are delivered to the receivers returned by [`MqttClient::events`].
+    pub async fn subscribe(&self, topic: &str) -> Result<(), MqttError> {
+        self.subscriptions.lock().unwrap().push(topic.to_owned());
+        self.client.subscribe(topic).await.with_whatever_context(|_| format!("Could not subscribe to topic {topic}"))
+    }
 
-        let (client, mut eventloop) = AsyncClient::new(mqtt_options, 10);
+    /// Whether the broker can be reached. Only noticed after the keep alive when the connection drops silently.
+    pub fn is_connected(&self) -> bool {
+        self.is_connected.load(Ordering::Relaxed)
+    }
+
+    pub fn events(&self) -> broadcast::Receiver<MqttEvent> {
+        self.events.subscribe()
+    }
+}
+
+// ////////// //
+// Event loop //
+// ////////// //
 
+/// Handles the events of the event loop, regardless of the protocol version.
+struct EventLoopHandler {
+    client: ProtocolClient,
+    availability_topic: Option<String>,
+    is_connected: Arc<AtomicBool>,
+    disconnected: Arc<Notify>,
+    subscriptions: Arc<Mutex<Vec<String>>>,
+    events: broadcast::Sender<MqttEvent>,
+    build_last_will: Arc<dyn Fn() -> MqttLastWill + Send + Sync>,
+    has_connected: bool,
+}
+
+impl EventLoopHandler {
+    fn on_connected(&mut self, session_present: bool) {
+        // Requests queued before the first connection, like the initial subscriptions, are sent now. Subscriptions are
+        // lost when the broker doesn't have our session (anymore), so restore them after reconnecting.
+        let is_reconnect = std::mem::replace(&mut self.has_connected, true);
+        let resubscribe_topics = match is_reconnect && !session_present {
+            true => self.subscriptions.lock().unwrap().clone(),
+            false => Vec::new(),
+        };
+        self.is_connected.store(true, Ordering::Relaxed);
+
+        // The request queue may be full, and the event loop has to keep running to empty it, so don't wait for it here.
+        let client = self.client.clone();
+        let availability_topic = self.availability_topic.clone();
         task::spawn(async move {
-            loop {
-                let (eventloop_ret, _) = {
-                    |mut eventloop: EventLoop| async move {
-                        let result = eventloop.poll().await;
-                        if let Err(e) = &result {
-                            error!("Connection error during MQTT event loop: {e}; Backing off...");
-                        }
-                        (eventloop, result)
-                    }
-                }.retry(ExponentialBuilder::default().with_factor(4.0)).context(eventloop).await;
-                eventloop = eventloop_ret;
+            // The broker may have published our last will since the previous connection.
+            if let Some(availability_topic) = availability_topic
+                && let Err(e) = client.publish(&availability_topic, AVAILABILITY_ONLINE.as_bytes(), MqttQos::AtLeastOnce, true, &availability_properties()).await {
+                error!("Could not publish availability: {e}");
+            }
+            for topic in resubscribe_topics {
+                if let Err(e) = client.subscribe(&topic).await {
+                    error!("Could not resubscribe to topic {topic}: {e}");
+                }
             }
         });
 
-        Self { client }
+        // Sending only fails when nobody is listening, which is fine.
+        let _ = self.events.send(MqttEvent::Connected { is_reconnect });
     }
 
-    pub async fn publish(&self, topic: &str, payload: &[u8]) -> Result<(), MqttError> {
-        Ok(self.client.publish(topic, QoS::AtLeastOnce, true, payload).await.with_whatever_context(|_| format!("Could not publish to topic {topic}"))?)
+    fn on_message(&self, topic: String, payload: Vec<u8>, retain: bool) {
+        debug!("Received MQTT message on topic {topic}");
+        let _ = self.events.send(MqttEvent::Message(MqttIncomingMessage { topic, payload, retain }));
+    }
+
+    fn on_disconnected(&self) {
+        self.is_connected.store(false, Ordering::Relaxed);
+        self.disconnected.notify_one();
+    }
+}
+
+async fn run_v311_event_loop(mut eventloop: EventLoop, mut handler: EventLoopHandler) {
+    loop {
+        let (eventloop_ret, result) = {
+            |mut eventloop: EventLoop| {
+                let is_connected = handler.is_connected.clone();
+                let build_last_will = handler.build_last_will.clone();
+                async move {
+                    // Polling while disconnected connects, with the last will for this connection.
+                    if !is_connected.load(Ordering::Relaxed) {
+                        let last_will = build_last_will();
+                        eventloop.mqtt_options.set_last_will(LastWill::new(last_will.topic, last_will.payload, QoS::AtLeastOnce, last_will.retain));
+                    }
+                    let result = eventloop.poll().await;
+                    if let Err(e) = &result {
+                        is_connected.store(false, Ordering::Relaxed);
+                        error!("Connection error during MQTT event loop: {e}; Backing off...");
+                    }
+                    (eventloop, result)
+                }
+            }
+        }.retry(ExponentialBuilder::default().with_factor(4.0)).context(eventloop).await;
+        eventloop = eventloop_ret;
+
+        match result {
+            Ok(Event::Incoming(Packet::ConnAck(conn_ack))) => handler.on_connected(conn_ack.session_present),
+            Ok(Event::Incoming(Packet::Publish(publish))) => handler.on_message(publish.topic, publish.payload.to_vec(), publish.retain),
+            Ok(Event::Outgoing(Outgoing::Disconnect)) => {
+                handler.on_disconnected();
+                break;
+            },
+            _ => {},
+        }
     }
 }
 
+async fn run_v5_event_loop(mut eventloop: v5::EventLoop, mut handler: EventLoopHandler) {
+    loop {
+        let (eventloop_ret, result) = {
+            |mut eventloop: v5::EventLoop| {
+                let is_connected = handler.is_connected.clone();
+                let build_last_will = handler.build_last_will.clone();
+                async move {
+                    // Polling while disconnected connects, with the last will for this connection.
+                    if !is_connected.load(Ordering::Relaxed) {
+                        let last_will = build_last_will();
+                        eventloop.options.set_last_will(v5::mqttbytes::v5::LastWill::new(last_will.topic, last_will.payload, v5::mqttbytes::QoS::AtLeastOnce, last_will.retain, None));
+                    }
+                    let result = eventloop.poll().await;
+                    if result.is_err() {
+                        is_connected.store(false, Ordering::Relaxed);
+                    }
+                    match &result {
+                        // MQTT v5 tells us why the connection was refused.
+                        Err(v5::ConnectionError::ConnectionRefused(reason_code)) => error!("MQTT broker refused the connection with reason {reason_code:?}; Backing off..."),
+                        Err(e) => error!("Connection error during MQTT event loop: {e}; Backing off..."),
+                        Ok(_) => {},
+                    }
+                    (eventloop, result)
+                }
+            }
+        }.retry(ExponentialBuilder::default().with_factor(4.0)).context(eventloop).await;
+        eventloop = eventloop_ret;
+
+        match result {
+            Ok(v5::Event::Incoming(v5::mqttbytes::v5::Packet::ConnAck(conn_ack))) => {
+                if let Some(reason) = conn_ack.properties.and_then(|p| p.reason_string) {
+                    debug!("MQTT broker accepted the connection: {reason}");
+                }
+                handler.on_connected(conn_ack.session_present);
+            },
+            Ok(v5::Event::Incoming(v5::mqttbytes::v5::Packet::Publish(publish))) => {
+                handler.on_message(String::from_utf8_lossy(&publish.topic).into_owned(), publish.payload.to_vec(), publish.retain);
+            },
+            Ok(v5::Event::Incoming(v5::mqttbytes::v5::Packet::Disconnect(disconnect))) => {
+                let reason = disconnect.properties.and_then(|p| p.reason_string).unwrap_or_default();
+                warn!("MQTT broker closed the connection with reason {:?} {reason}", disconnect.reason_code);
+            },
+            Ok(v5::Event::Outgoing(Outgoing::Disconnect)) => {
+                handler.on_disconnected();
+                break;
+            },
+            _ => {},
+        }
+    }
+}
+
+// /////////////// //
+// Protocol client //
+// /////////////// //
+
+/// The rumqttc client for the configured protocol version.
+#[derive(Clone)]
+enum ProtocolClient {
+    V311(AsyncClient),
+    V5(v5::AsyncClient),
+}
+
+impl ProtocolClient {
+    async fn publish(&self, topic: &str, payload: &[u8], qos: MqttQos, retain: bool, properties: &MqttMessageProperties) -> Result<(), MqttError> {
+        match self {
+            ProtocolClient::V311(client) => client.publish(topic, qos.into(), retain, payload).await.whatever_context("Could not publish"),
+            ProtocolClient::V5(client) => client.publish_with_properties(topic, qos.into(), retain, payload.to_vec(), build_v5_publish_properties(properties)).await.whatever_context("Could not publish"),
+        }
+    }
+
+    async fn subscribe(&self, topic: &str) -> Result<(), MqttError> {
+        match self {
+            ProtocolClient::V311(client) => client.subscribe(topic, QoS::AtLeastOnce).await.whatever_context("Could not subscribe"),
+            ProtocolClient::V5(client) => client.subscribe(topic, v5::mqttbytes::QoS::AtLeastOnce).await.whatever_context("Could not subscribe"),
+        }
+    }
+
+    async fn disconnect(&self) -> Result<(), MqttError> {
+        match self {
+            ProtocolClient::V311(client) => client.disconnect().await.whatever_context("Could not disconnect"),
+            ProtocolClient::V5(client) => client.disconnect().await.whatever_context("Could not disconnect"),
+        }
+    }
+}
+
+// /////// //
+// Helpers //
+// /////// //
+
+fn build_transport(mqtt_settings: &Mqtt) -> Result<Transport, MqttError> {
+    let tls_configuration = || -> Result<rumqttc::TlsConfiguration, MqttError> {
+        let config = build_client_config(&mqtt_settings.tls_options()).with_whatever_context(|e| format!("Could not set up TLS for MQTT: {e}"))?;
+        Ok(rumqttc::TlsConfiguration::Rustls(Arc::new(config)))
+    };
+    Ok(match (mqtt_settings.transport, mqtt_settings.secure) {
+        (MqttTransport::Tcp, true) => Transport::tls_with_config(tls_configuration()?),
+        (MqttTransport::Tcp, false) => Transport::tcp(),
+        (MqttTransport::WebSocket, true) => Transport::wss_with_config(tls_configuration()?),
+        (MqttTransport::WebSocket, false) => Transport::ws(),
+    })
+}
+
+/// For WebSockets rumqttc expects the full URL as broker address, e.g. `wss://broker.example.com:443/mqtt`.
+fn build_broker_address(mqtt_settings: &Mqtt) -> String {
+    match (mqtt_settings.transport, mqtt_settings.secure) {
+        (MqttTransport::Tcp, _) => mqtt_settings.host.to_owned(),
+        (MqttTransport::WebSocket, secure) => format!(
+            "{}://{}:{}/{}",
+            if secure { "wss" } else { "ws" },
+            mqtt_settings.host,
+            mqtt_settings.port,
+            mqtt_settings.websocket_path.trim_start_matches('/'),
+        ),
+    }
+}
+
+fn build_websocket_headers(mqtt_settings: &Mqtt) -> Result<Vec<(HeaderName, HeaderValue)>, MqttError> {
+    mqtt_settings.websocket_headers.iter()
+        .map(|(name, value)| Ok((
+            HeaderName::try_from(name).with_whatever_context(|_| format!("Invalid WebSocket header name {name}"))?,
+            HeaderValue::try_from(value).with_whatever_context(|_| format!("Invalid value for WebSocket header {name}"))?,
+        )))
+        .collect()
+}
+
+async fn add_websocket_headers(mut request: http::Request<()>, headers: Vec<(HeaderName, HeaderValue)>) -> http::Request<()> {
+    request.headers_mut().extend(headers);
+    request
+}
+
+impl From<MqttQos> for QoS {
+    fn from(qos: MqttQos) -> Self {
+        match qos {
+            MqttQos::AtMostOnce => QoS::AtMostOnce,
+            MqttQos::AtLeastOnce => QoS::AtLeastOnce,
+            MqttQos::ExactlyOnce => QoS::ExactlyOnce,
+        }
+    }
+}
+
+impl From<MqttQos> for v5::mqttbytes::QoS {
+    fn from(qos: MqttQos) -> Self {
+        match qos {
+            MqttQos::AtMostOnce => v5::mqttbytes::QoS::AtMostOnce,
+            MqttQos::AtLeastOnce => v5::mqttbytes::QoS::AtLeastOnce,
+            MqttQos::ExactlyOnce => v5::mqttbytes::QoS::ExactlyOnce,
+        }
+    }
+}
+
+fn build_v5_publish_properties(properties: &MqttMessageProperties) -> v5::mqttbytes::v5::PublishProperties {
+    v5::mqttbytes::v5::PublishProperties {
+        content_type: properties.content_type.clone(),
+        message_expiry_interval: properties.message_expiry.map(|expiry| expiry.as_secs().try_into().unwrap_or(u32::MAX)),
+        user_properties: properties.user_properties.clone(),
+        ..Default::default()
+    }
+}
+
+fn availability_properties() -> MqttMessageProperties {
+    MqttMessageProperties { content_type: Some("text/plain".to_owned()), ..Default::default() }
+}
+
 // ////// //
 // Errors //
 // ////// //
@@ -1,6 +1,9 @@
-use std::sync::{Arc, OnceLock};
+use std::{fs, path::PathBuf, sync::{Arc, Mutex, OnceLock}, time::SystemTime};
 
-use rumqttc::tokio_rustls::rustls;
+use log::{error, info};
+use rumqttc::tokio_rustls::rustls::{self, pki_types::pem::PemObject};
+use sha2::{Digest, Sha256};
+use snafu::{whatever, ResultExt, Snafu};
 
 // //////////////////////// //
 // Load system certificates //
@@ -17,6 +20,326 @@ pub fn get_system_certs() -> &'static Arc<rustls::RootCertStore> {
     })
 }
 
+// ///////////////// //
+// Client TLS config //
+// ///////////////// //
+
+/// Where to find the certificates for a TLS connection, all PEM files.
+#[derive(Debug, Clone, Default)]
+pub struct TlsOptions {
+    pub ignore_tls_errors: bool,
+    /// CA bundle to verify the server with instead of the system certificates.
+    pub ca_file: Option<PathBuf>,
+    pub client_cert_file: Option<PathBuf>,
+    pub client_key_file: Option<PathBuf>,
+    /// SHA-256 fingerprint of the server certificate or its public key, trusted instead of a CA.
+    pub pinned_fingerprint: Option<String>,
+    /// Name to verify the server certificate for, instead of the host connected to. The handshake still sends the host
+    /// as server name (SNI), as neither rumqttc nor reqwest allow changing it.
+    pub verify_server_name: Option<String>,
+}
+
+/// Builds a rustls config for `options`. The CA bundle and client certificate are read again when their files change,
+/// so renewed certificates are used for the next connection without a restart.
+pub fn build_client_config(options: &TlsOptions) -> Result<rustls::ClientConfig, TlsError> {
+    let builder = rustls::ClientConfig::builder();
+    let provider = builder.crypto_provider().clone();
+
+    let verifier: Arc<dyn rustls::client::danger::ServerCertVerifier> = match (options.ignore_tls_errors, &options.ca_file) {
+        // TLS with a pinned certificate, which also works for self-signed certificates.
+        _ if let Some(fingerprint) = options.pinned_fingerprint.as_deref().filter(|f| !f.is_empty()) => {
+            Arc::new(PinnedServerCertVerifier::new(fingerprint, provider.clone())?)
+        },
+        // TLS with certificate verification against a custom CA. Configuring a CA only makes sense to verify with it, so it
+        // wins over ignoring TLS errors, which is the default for CUPS.
+        (_, Some(ca_file)) => Arc::new(ReloadingServerCertVerifier::new(ca_file.clone(), provider.clone())?),
+        // TLS without certificate verification.
+        (true, None) => Arc::new(NoopServerCertVerifier {}),
+        // TLS with certificate verification against the system certificates.
+        (false, None) => rustls::client::WebPkiServerVerifier::builder_with_provider(get_system_certs().clone(), provider.clone()).build()
+            .with_whatever_context(|_| "Could not use the system certificates")?,
+    };
+    let verifier = match options.verify_server_name.as_deref().filter(|name| !name.is_empty()) {
+        Some(server_name) => Arc::new(ServerNameOverrideVerifier::new(server_name, verifier)?),
+        None => verifier,
+    };
+    let builder = builder.dangerous().with_custom_certificate_verifier(verifier);
+
+    match (&options.client_cert_file, &options.client_key_file) {
+        (Some(cert_file), Some(key_file)) => Ok(builder.with_client_cert_resolver(Arc::new(ReloadingClientCertResolver::new(cert_file.clone(), key_file.clone(), provider)?))),
+        (None, None) => Ok(builder.with_no_client_auth()),
+        _ => whatever!("Both a client certificate and a client key are needed for client authentication"),
+    }
+}
+
+// ////////////////////// //
+// Reloading certificates //
+// ////////////////////// //
+
+/// Something loaded from files, which is loaded again when any of the files is modified.
+#[derive(Debug)]
+struct ReloadingFiles<T> {
+    paths: Vec<PathBuf>,
+    current: Mutex<(Vec<Option<SystemTime>>, T)>,
+}
+
+impl<T: Clone> ReloadingFiles<T> {
+    fn new(paths: Vec<PathBuf>, load: impl Fn() -> Result<T, TlsError>) -> Result<Self, TlsError> {
+        let modified = get_modified_times(&paths);
+        Ok(Self { paths, current: Mutex::new((modified, load()?)) })
+    }
+
+    /// Returns the loaded value, reloading it first when the files were modified. If reloading fails (e.g. because only
+    /// one of the files has been written so far), the previous value is kept and reloading is tried again next time.
+    fn get(&self, load: impl Fn() -> Result<T, TlsError>) -> T {
+        let mut current = self.current.lock().unwrap();
+        let modified = get_modified_times(&self.paths);
+        if modified != current.0 {
+            match load() {
+                Ok(value) => {
+                    info!("Reloaded {}", self.paths.iter().map(|p| p.display().to_string()).collect::<Vec<_>>().join(", "));
+                    *current = (modified, value);
+                },
+                Err(e) => error!("Could not reload certificates, keeping the previous ones: {e}"),
+            }
+        }
+        current.1.clone()
+    }
+}
+
+fn get_modified_times(paths: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    paths.iter().map(|path| fs::metadata(path).and_then(|m| m.modified()).ok()).collect()
+}
+
+fn load_certs(path: &PathBuf) -> Result<Vec<rustls::pki_types::CertificateDer<'static>>, TlsError> {
+    let certs = rustls::pki_types::CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_whatever_context(|_| format!("Could not read certificates from {}", path.display()))?;
+    if certs.is_empty() {
+        whatever!("No certificates found in {}", path.display());
+    }
+    Ok(certs)
+}
+
+/// Verifies server certificates against a CA bundle, which is reloaded when the file changes.
+#[derive(Debug)]
+struct ReloadingServerCertVerifier {
+    ca_file: PathBuf,
+    provider: Arc<rustls::crypto::CryptoProvider>,
+    verifier: ReloadingFiles<Arc<rustls::client::WebPkiServerVerifier>>,
+}
+
+impl ReloadingServerCertVerifier {
+    fn new(ca_file: PathBuf, provider: Arc<rustls::crypto::CryptoProvider>) -> Result<Self, TlsError> {
+        let verifier = ReloadingFiles::new(vec![ca_file.clone()], || Self::load(&ca_file, &provider))?;
+        Ok(Self { ca_file, provider, verifier })
+    }
+
+    fn load(ca_file: &PathBuf, provider: &Arc<rustls::crypto::CryptoProvider>) -> Result<Arc<rustls::client::WebPkiServerVerifier>, TlsError> {
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in load_certs(ca_file)? {
+            roots.add(cert).with_whatever_context(|_| format!("Invalid CA certificate in {}", ca_file.display()))?;
+        }
+        rustls::client::WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone()).build()
+            .with_whatever_context(|_| format!("Could not use CA bundle {}", ca_file.display()))
+    }
+
+    fn current(&self) -> Arc<rustls::client::WebPkiServerVerifier> {
+        self.verifier.get(|| Self::load(&self.ca_file, &self.provider))
+    }
+}
+
+impl rustls::client::danger::ServerCertVerifier for ReloadingServerCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::pki_types::CertificateDer<'_>,
+        intermediates: &[rustls::pki_types::CertificateDer<'_>],
+        server_name: &rustls::pki_types::ServerName<'_>,
+        ocsp_response: &[u8],
+        now: rustls::pki_types::UnixTime,
+    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+        self.current().verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        self.current().verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        self.current().verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.current().supported_verify_schemes()
+    }
+}
+
+/// Offers a client certificate and key, which are reloaded when either file changes.
+#[derive(Debug)]
+struct ReloadingClientCertResolver {
+    cert_file: PathBuf,
+    key_file: PathBuf,
+    provider: Arc<rustls::crypto::CryptoProvider>,
+    certified_key: ReloadingFiles<Arc<rustls::sign::CertifiedKey>>,
+}
+
+impl ReloadingClientCertResolver {
+    fn new(cert_file: PathBuf, key_file: PathBuf, provider: Arc<rustls::crypto::CryptoProvider>) -> Result<Self, TlsError> {
+        let certified_key = ReloadingFiles::new(vec![cert_file.clone(), key_file.clone()], || Self::load(&cert_file, &key_file, &provider))?;
+        Ok(Self { cert_file, key_file, provider, certified_key })
+    }
+
+    fn load(cert_file: &PathBuf, key_file: &PathBuf, provider: &Arc<rustls::crypto::CryptoProvider>) -> Result<Arc<rustls::sign::CertifiedKey>, TlsError> {
+        let certs = load_certs(cert_file)?;
+        let key = rustls::pki_types::PrivateKeyDer::from_pem_file(key_file).with_whatever_context(|_| format!("Could not read private key from {}", key_file.display()))?;
+        let certified_key = rustls::sign::CertifiedKey::from_der(certs, key, provider)
+            .with_whatever_context(|_| format!("Client certificate {} doesn't match key {}", cert_file.display(), key_file.display()))?;
+        Ok(Arc::new(certified_key))
+    }
+}
+
+impl rustls::client::ResolvesClientCert for ReloadingClientCertResolver {
+    fn resolve(&self, _root_hint_subjects: &[&[u8]], _sigschemes: &[rustls::SignatureScheme]) -> Option<Arc<rustls::sign::CertifiedKey>> {
+        Some(self.certified_key.get(|| Self::load(&self.cert_file, &self.key_file, &self.provider)))
+    }
+
+    fn has_certs(&self) -> bool {
+        true
+    }
+}
+
+// ////////////////////////////// //
+// Server name override verifier //
+// ////////////////////////////// //
+
+/// Verifies the server certificate for a fixed server name, e.g. when connecting by an IP address which isn't in the
+/// certificate. Only the verification uses this name, the connection is still made to the configured host.
+#[derive(Debug)]
+struct ServerNameOverrideVerifier {
+    server_name: rustls::pki_types::ServerName<'static>,
+    inner: Arc<dyn rustls::client::danger::ServerCertVerifier>,
+}
+
+impl ServerNameOverrideVerifier {
+    fn new(server_name: &str, inner: Arc<dyn rustls::client::danger::ServerCertVerifier>) -> Result<Self, TlsError> {
+        let server_name = rustls::pki_types::ServerName::try_from(server_name.to_owned())
+            .with_whatever_context(|_| format!("{server_name} is not a valid TLS server name"))?;
+        Ok(Self { server_name, inner })
+    }
+}
+
+impl rustls::client::danger::ServerCertVerifier for ServerNameOverrideVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::pki_types::CertificateDer<'_>,
+        intermediates: &[rustls::pki_types::CertificateDer<'_>],
+        _server_name: &rustls::pki_types::ServerName<'_>,
+        ocsp_response: &[u8],
+        now: rustls::pki_types::UnixTime,
+    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+        self.inner.verify_server_cert(end_entity, intermediates, &self.server_name, ocsp_response, now)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+// ////////////////////////////////// //
+// Pinned server certificate verifier //
+// ////////////////////////////////// //
+
+/// Only trusts a server whose certificate, or the public key in it, has the pinned SHA-256 fingerprint. The server name and
+/// validity aren't checked, as pinned certificates are usually self-signed, but the handshake signatures are.
+#[derive(Debug)]
+pub struct PinnedServerCertVerifier {
+    fingerprint: Vec<u8>,
+    provider: Arc<rustls::crypto::CryptoProvider>,
+}
+
+impl PinnedServerCertVerifier {
+    /// Accepts the fingerprint as hex, optionally separated by colons like `openssl x509 -fingerprint -sha256` prints it.
+    pub fn new(fingerprint: &str, provider: Arc<rustls::crypto::CryptoProvider>) -> Result<Self, TlsError> {
+        let hex = fingerprint.chars().filter(|c| *c != ':' && !c.is_whitespace()).collect::<String>();
+        if hex.len() != 64 || !hex.is_ascii() {
+            whatever!("Pinned fingerprint {fingerprint} is not a SHA-256 fingerprint");
+        }
+        let fingerprint = (0..hex.len()).step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<Result<Vec<_>, _>>()
+            .with_whatever_context(|_| format!("Pinned fingerprint {fingerprint} is not hexadecimal"))?;
+        Ok(Self { fingerprint, provider })
+    }
+}
+
+impl rustls::client::danger::ServerCertVerifier for PinnedServerCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::pki_types::CertificateDer<'_>,
+        _intermediates: &[rustls::pki_types::CertificateDer<'_>],
+        _server_name: &rustls::pki_types::ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: rustls::pki_types::UnixTime,
+    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+        let public_key = rustls::server::ParsedCertificate::try_from(end_entity)?.subject_public_key_info();
+        if Sha256::digest(end_entity).as_slice() == self.fingerprint || Sha256::digest(&public_key).as_slice() == self.fingerprint {
+            Ok(rustls::client::danger::ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(rustls::CertificateError::ApplicationVerificationFailure))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
 // //////////////////////////////// //
 // NOOP server certificate verifier //
 // //////////////////////////////// //
@@ -72,3 +395,110 @@ impl rustls::client::danger::ServerCertVerifier for NoopServerCertVerifier {
         ]
     }
 }
+
+// ////// //
+// Errors //
+// ////// //
+
+#[derive(Debug, Snafu)]
+pub enum TlsError {
+    #[snafu(whatever, display("{message}"))]
+    Whatever {
+        message: String,
+        #[snafu(source(from(Box<dyn std::error::Error + Send + Sync>, Some)))]
+        source: Option<Box<dyn std::error::Error + Send + Sync>>,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FINGERPRINT: &str = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    fn get_provider() -> Arc<rustls::crypto::CryptoProvider> {
+        rustls::ClientConfig::builder().crypto_provider().clone()
+    }
+
+    /// Remembers the server name the certificate was verified for.
+    #[derive(Debug, Default)]
+    struct RecordingServerCertVerifier {
+        server_name: std::sync::Mutex<Option<String>>,
+    }
+
+    impl rustls::client::danger::ServerCertVerifier for RecordingServerCertVerifier {
+        fn verify_server_cert(
+            &self,
+            _end_entity: &rustls::pki_types::CertificateDer<'_>,
+            _intermediates: &[rustls::pki_types::CertificateDer<'_>],
+            server_name: &rustls::pki_types::ServerName<'_>,
+            _ocsp_response: &[u8],
+            _now: rustls::pki_types::UnixTime,
+        ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+            *self.server_name.lock().unwrap() = Some(server_name.to_str().into_owned());
+            Ok(rustls::client::danger::ServerCertVerified::assertion())
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            _message: &[u8],
+            _cert: &rustls::pki_types::CertificateDer<'_>,
+            _dss: &rustls::DigitallySignedStruct,
+        ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+            Ok(rustls::client::danger::HandshakeSignatureValid::assertion())
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            _message: &[u8],
+            _cert: &rustls::pki_types::CertificateDer<'_>,
+            _dss: &rustls::DigitallySignedStruct,
+        ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+            Ok(rustls::client::danger::HandshakeSignatureValid::assertion())
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+            Vec::new()
+        }
+    }
+
+    fn with_colons(hex: &str) -> String {
+        hex.as_bytes().chunks(2).map(|pair| std::str::from_utf8(pair).unwrap()).collect::<Vec<_>>().join(":")
+    }
+
+    #[test]
+    fn parses_fingerprint_without_colons() {
+        let verifier = PinnedServerCertVerifier::new(FINGERPRINT, get_provider()).unwrap();
+        assert_eq!(verifier.fingerprint.len(), 32);
+        assert_eq!(verifier.fingerprint[..2], [0x01, 0x23]);
+    }
+
+    #[test]
+    fn parses_fingerprint_with_colons() {
+        let verifier = PinnedServerCertVerifier::new(&with_colons(&FINGERPRINT.to_uppercase()), get_provider()).unwrap();
+        assert_eq!(verifier.fingerprint, PinnedServerCertVerifier::new(FINGERPRINT, get_provider()).unwrap().fingerprint);
+    }
+
+    #[test]
+    fn rejects_bad_fingerprints() {
+        for fingerprint in ["", "0123", &FINGERPRINT[2..], &format!("{FINGERPRINT}00"), &FINGERPRINT.replace('a', "g"), &FINGERPRINT.replacen("01", "é", 1)] {
+            assert!(PinnedServerCertVerifier::new(fingerprint, get_provider()).is_err(), "accepted {fingerprint}");
+        }
+    }
+
+    #[test]
+    fn verifies_for_overridden_server_name() {
+        use rustls::client::danger::ServerCertVerifier;
+
+        let inner = Arc::new(RecordingServerCertVerifier::default());
+        let verifier = ServerNameOverrideVerifier::new("broker.example.com", inner.clone()).unwrap();
+        let host = rustls::pki_types::ServerName::try_from("192.168.1.10").unwrap();
+        verifier.verify_server_cert(&rustls::pki_types::CertificateDer::from(vec![]), &[], &host, &[], rustls::pki_types::UnixTime::now()).unwrap();
+        assert_eq!(inner.server_name.lock().unwrap().as_deref(), Some("broker.example.com"));
+    }
+
+    #[test]
+    fn rejects_invalid_server_name() {
+        assert!(ServerNameOverrideVerifier::new("not a name", Arc::new(RecordingServerCertVerifier::default())).is_err());
+    }
+}
@@ -1,7 +1,9 @@
-use ipp::model::PrinterState;
+use std::collections::BTreeMap;
+
+use ipp::model::{JobState, PrinterState};
 use serde::{Deserialize, Serialize};
 
-use crate::cups_client::models::{IppPrintQueueState, IppPrinterMarker};
+use crate::cups_client::models::{IppDevice, IppJob, IppPrintQueueState, IppPrinterMarker};
 
 // ////// //
 // Status //
@@ -12,6 +14,8 @@ pub struct MqttCupsServerStatus {
     pub is_reachable: bool,
     pub cups_version: Option<String>,
     pub cups2mqtt_version: String,
+    #[serde(flatten)]
+    pub timestamps: Option<MqttTimestamps>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -24,6 +28,20 @@ pub struct MqttCupsPrintQueueStatus {
     pub state_message: String,
     pub state_reason: String,
     pub markers: Vec<MqttCupsPrinterMarker>,
+    #[serde(flatten)]
+    pub timestamps: Option<MqttTimestamps>,
+}
+
+/// When a status was last updated, changed and polled. Only kept since startup, so after a restart these are the startup time.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct MqttTimestamps {
+    /// When any of the other fields last changed (RFC 3339).
+    pub last_updated: String,
+    /// When the state (reachability for the server) last changed (RFC 3339).
+    pub last_changed: String,
+    /// When CUPS was asked for this status (RFC 3339), only when the status is published on every poll.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub polled_at: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -61,7 +79,8 @@ impl From<&IppPrintQueueState> for MqttCupsPrintQueueStatus {
                 color: m.color.clone(),
                 name: m.name.clone(),
                 level: m.level,
-            }).collect()
+            }).collect(),
+            timestamps: None,
         }
     }
 }
@@ -77,6 +96,295 @@ impl From<&IppPrinterMarker> for MqttCupsPrinterMarker {
     }
 }
 
+// //// //
+// Jobs //
+// //// //
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct MqttCupsJobStatus {
+    pub job_id: i32,
+    pub queue: String,
+    pub name: String,
+    pub state: MqttCupsJobState,
+    pub state_reasons: Vec<String>,
+    pub state_message: Option<String>,
+    pub impressions_completed: Option<i32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Copy)]
+pub enum MqttCupsJobState {
+    Pending = 3,
+    PendingHeld = 4,
+    Processing = 5,
+    ProcessingStopped = 6,
+    Canceled = 7,
+    Aborted = 8,
+    Completed = 9,
+}
+
+impl MqttCupsJobState {
+    /// Whether the job is done, successfully or not, and won't change state anymore.
+    pub fn is_final(&self) -> bool {
+        matches!(self, MqttCupsJobState::Canceled | MqttCupsJobState::Aborted | MqttCupsJobState::Completed)
+    }
+}
+
+impl MqttCupsJobStatus {
+    pub fn from_ipp_job(queue_name: &str, job: &IppJob) -> Self {
+        MqttCupsJobStatus {
+            job_id: job.job_id,
+            queue: queue_name.to_owned(),
+            name: job.name.clone(),
+            state: match job.state {
+                JobState::Pending => MqttCupsJobState::Pending,
+                JobState::PendingHeld => MqttCupsJobState::PendingHeld,
+                JobState::Processing => MqttCupsJobState::Processing,
+                JobState::ProcessingStopped => MqttCupsJobState::ProcessingStopped,
+                JobState::Canceled => MqttCupsJobState::Canceled,
+                JobState::Aborted => MqttCupsJobState::Aborted,
+                JobState::Completed => MqttCupsJobState::Completed,
+            },
+            state_reasons: job.state_reasons.clone(),
+            state_message: job.state_message.clone(),
+            impressions_completed: job.impressions_completed,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MqttCupsStuckJobs {
+    pub is_stuck: bool,
+    pub jobs: Vec<MqttCupsStuckJob>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MqttCupsStuckJob {
+    #[serde(flatten)]
+    pub job: MqttCupsJobStatus,
+    /// When the job was last seen making progress (RFC 3339).
+    pub stuck_since: String,
+}
+
+// ///// //
+// Audit //
+// ///// //
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MqttAuditEvent {
+    pub timestamp: String,
+    pub queue: String,
+    pub action: MqttAuditAction,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attempt: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub job_id: Option<i32>,
+    pub is_success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum MqttAuditAction {
+    ResumePrinter,
+    RetryJob,
+    CancelJob,
+    Recovered,
+}
+
+// //////// //
+// Failover //
+// //////// //
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MqttFailoverStatus {
+    pub is_failed_over: bool,
+    pub standby_queue: String,
+    pub moved_job_ids: Vec<i32>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MqttFailoverEvent {
+    pub timestamp: String,
+    pub queue: String,
+    pub standby_queue: String,
+    pub direction: MqttFailoverDirection,
+    pub job_ids: Vec<i32>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum MqttFailoverDirection {
+    Failover,
+    Failback,
+}
+
+// /////// //
+// Devices //
+// /////// //
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MqttCupsDevices {
+    pub devices: Vec<MqttCupsDevice>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MqttCupsDevice {
+    pub device_uri: String,
+    pub device_class: String,
+    pub info: Option<String>,
+    pub make_and_model: Option<String>,
+    pub device_id: Option<String>,
+    pub location: Option<String>,
+}
+
+impl From<&IppDevice> for MqttCupsDevice {
+    fn from(device: &IppDevice) -> Self {
+        MqttCupsDevice {
+            device_uri: device.device_uri.clone(),
+            device_class: device.device_class.clone(),
+            info: device.info.clone(),
+            make_and_model: device.make_and_model.clone(),
+            device_id: device.device_id.clone(),
+            location: device.location.clone(),
+        }
+    }
+}
+
+// ////// //
+// Outbox //
+// ////// //
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MqttOutboxStatus {
+    /// Events still waiting to be published.
+    pub queued: usize,
+    /// Events dropped since startup because the outbox was full.
+    pub dropped: u64,
+    /// Events published after reconnecting since startup.
+    pub replayed: u64,
+}
+
+// ////////////////// //
+// Queue provisioning //
+// ////////////////// //
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MqttQueueProvisioningReport {
+    pub queues: Vec<MqttQueueProvisioningStatus>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MqttQueueProvisioningStatus {
+    pub name: String,
+    pub status: MqttQueueProvisioningState,
+    pub drift: Vec<MqttQueueProvisioningDrift>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MqttQueueProvisioningDrift {
+    pub attribute: String,
+    pub expected: String,
+    pub actual: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub enum MqttQueueProvisioningState {
+    InSync,
+    Missing,
+    Drifted,
+    Created,
+    Updated,
+    Failed,
+}
+
+// ///// //
+// Homie //
+// ///// //
+
+/// The `$description` of a Homie 5 device, also used to build the attributes of a Homie 4 device.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HomieDeviceDescription {
+    pub homie: String,
+    pub version: u64,
+    pub name: String,
+    pub nodes: BTreeMap<String, HomieNodeDescription>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HomieNodeDescription {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub node_type: String,
+    pub properties: BTreeMap<String, HomiePropertyDescription>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HomiePropertyDescription {
+    pub name: String,
+    pub datatype: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub settable: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unit: Option<String>,
+}
+
+// /////////// //
+// Sparkplug B //
+// /////////// //
+
+/// The Sparkplug B `Payload` protobuf message, with only the fields cups2mqtt uses.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SparkplugPayload {
+    /// Milliseconds since the Unix epoch.
+    #[prost(uint64, optional, tag = "1")]
+    pub timestamp: Option<u64>,
+    #[prost(message, repeated, tag = "2")]
+    pub metrics: Vec<SparkplugMetric>,
+    /// Sequence number of the message, from 0 to 255. Not set on NDEATH messages.
+    #[prost(uint64, optional, tag = "3")]
+    pub seq: Option<u64>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SparkplugMetric {
+    #[prost(string, optional, tag = "1")]
+    pub name: Option<String>,
+    #[prost(uint64, optional, tag = "3")]
+    pub timestamp: Option<u64>,
+    /// One of the [`SparkplugDataType`] values.
+    #[prost(uint32, optional, tag = "4")]
+    pub datatype: Option<u32>,
+    #[prost(bool, optional, tag = "7")]
+    pub is_null: Option<bool>,
+    #[prost(oneof = "SparkplugMetricValue", tags = "10, 11, 14, 15")]
+    pub value: Option<SparkplugMetricValue>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof)]
+pub enum SparkplugMetricValue {
+    /// For the Int8 through UInt32 data types, signed values in two's complement.
+    #[prost(uint32, tag = "10")]
+    Int(u32),
+    /// For the Int64, UInt64 and DateTime data types.
+    #[prost(uint64, tag = "11")]
+    Long(u64),
+    #[prost(bool, tag = "14")]
+    Boolean(bool),
+    #[prost(string, tag = "15")]
+    String(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SparkplugDataType {
+    Int32 = 3,
+    UInt64 = 8,
+    Boolean = 11,
+    String = 12,
+}
+
 // ////////////// //
 // Home Assistant //
 // ////////////// //
@@ -87,10 +395,29 @@ pub struct HomeAssistantDiscoverySensorPayload {
     pub state_topic: String,
     pub unique_id: String,
     pub device: HomeAssistantDevice,
-    pub value_template: String
+    pub value_template: String,
+    /// Seconds after which Home Assistant considers the value unavailable when no new one was received.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expire_after: Option<u64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub availability: Vec<HomeAssistantAvailability>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HomeAssistantDiscoveryBinarySensorPayload {
+    pub name: String,
+    pub state_topic: String,
+    pub unique_id: String,
+    pub device: HomeAssistantDevice,
+    pub value_template: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_class: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub availability: Vec<HomeAssistantAvailability>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[allow(unused)]
 pub struct HomeAssistantDiscoveryDeviceTriggerPayload {
     pub automation_type: String,
     pub payload: String,
@@ -100,6 +427,18 @@ pub struct HomeAssistantDiscoveryDeviceTriggerPayload {
     pub device: HomeAssistantDevice,
 }
 
+/// Uses the HA defaults `online` and `offline` as payloads, unless set.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct HomeAssistantAvailability {
+    pub topic: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload_available: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload_not_available: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value_template: Option<String>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct HomeAssistantDevice {
     pub identifiers: Vec<String>,
@@ -0,0 +1,242 @@
+use std::{collections::VecDeque, fs, io::ErrorKind, sync::{atomic::{AtomicBool, Ordering}, Mutex, OnceLock}};
+
+use backon::{ExponentialBuilder, Retryable};
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+
+use crate::{
+    codec,
+    config::models::PublishPolicy,
+    get_mqtt_client,
+    get_settings,
+    mqtt_client::{client::MqttMessageProperties, models::MqttOutboxStatus},
+    publish_status,
+    topics::{self, MessageClass},
+    ApplicationError,
+};
+
+#[derive(Debug, Serialize, Deserialize)]
+struct OutboxMessage {
+    topic: String,
+    payload: String,
+    /// The properties the event would have been published with. Missing in events buffered by older versions.
+    #[serde(default)]
+    properties: MqttMessageProperties,
+    /// When the event was buffered (RFC 3339), to count the time in the outbox towards its message expiry.
+    #[serde(default)]
+    buffered_at: String,
+}
+
+#[derive(Debug, Default)]
+struct Outbox {
+    messages: VecDeque<OutboxMessage>,
+    /// Total length of the buffered payloads.
+    size: usize,
+    dropped: u64,
+    replayed: u64,
+}
+
+fn get_outbox() -> &'static Mutex<Outbox> {
+    static OUTBOX: OnceLock<Mutex<Outbox>> = OnceLock::new();
+    OUTBOX.get_or_init(|| Mutex::new(load_outbox()))
+}
+
+// ///////// //
+// Buffering //
+// ///////// //
+
+/// Whether an event has to be buffered instead of published: when the broker can't be reached, or when older events
+/// still have to be published first.
+pub fn should_buffer() -> bool {
+    get_settings().mqtt.outbox.max_messages > 0 && (!get_mqtt_client().is_connected() || !get_outbox().lock().unwrap().messages.is_empty())
+}
+
+/// Buffers an event until it can be published with `properties`, dropping the oldest events when the outbox is full.
+pub fn push(topic: &str, payload: String, properties: MqttMessageProperties) {
+    let mut outbox = get_outbox().lock().unwrap();
+    outbox.size += payload.len();
+    outbox.messages.push_back(OutboxMessage { topic: topic.to_owned(), payload, properties, buffered_at: Utc::now().to_rfc3339() });
+
+    let dropped = drop_oldest(&mut outbox);
+    if dropped > 0 {
+        warn!("Outbox is full, dropped the oldest {dropped} event(s)");
+    }
+    save_outbox(&outbox);
+    drop(outbox);
+
+    // Buffered while connected, as an earlier replay failed, so don't wait for the next reconnect to try again.
+    if get_mqtt_client().is_connected() {
+        tokio::spawn(async {
+            if let Err(e) = replay().await {
+                error!("Failed to publish buffered events: {e}");
+            }
+        });
+    }
+}
+
+/// Drops the oldest events until the outbox is within its limits, returns how many were dropped.
+fn drop_oldest(outbox: &mut Outbox) -> u64 {
+    let settings = &get_settings().mqtt.outbox;
+    let mut dropped = 0;
+    while outbox.messages.len() > settings.max_messages || outbox.size > settings.max_bytes {
+        let Some(message) = outbox.messages.pop_front() else { break };
+        outbox.size -= message.payload.len();
+        dropped += 1;
+    }
+    outbox.dropped += dropped;
+    dropped
+}
+
+// ///////// //
+// Replaying //
+// ///////// //
+
+/// Publishes the buffered events in the order they happened, followed by the outbox status. Meant to run after every
+/// (re)connect. Retried while connected, as new events are buffered until the outbox is empty.
+pub async fn replay() -> Result<(), ApplicationError> {
+    static IS_REPLAYING: AtomicBool = AtomicBool::new(false);
+    if IS_REPLAYING.swap(true, Ordering::SeqCst) {
+        return Ok(());
+    }
+    let result = replay_messages.retry(ExponentialBuilder::default().with_factor(4.0))
+        .when(|_| get_mqtt_client().is_connected())
+        .await;
+    IS_REPLAYING.store(false, Ordering::SeqCst);
+    result?;
+
+    publish_outbox_status().await
+}
+
+async fn replay_messages() -> Result<(), ApplicationError> {
+    let policy = MessageClass::Event.policy();
+    let mut replayed = 0;
+    let mut expired = 0;
+    let mut result = Ok(());
+
+    while get_mqtt_client().is_connected() {
+        // Taken out before publishing, as new events may push out the oldest ones meanwhile.
+        let Some(message) = pop_front() else { break };
+        // Put back on any error, so it's published on the next attempt instead of being lost.
+        match publish_message(&message, policy).await {
+            Ok(true) => replayed += 1,
+            Ok(false) => expired += 1,
+            Err(e) => {
+                push_front(message);
+                result = Err(e);
+                break;
+            },
+        }
+    }
+
+    if replayed > 0 || expired > 0 {
+        let mut outbox = get_outbox().lock().unwrap();
+        outbox.replayed += replayed;
+        outbox.dropped += expired;
+        save_outbox(&outbox);
+        info!("Published {replayed} buffered event(s), {} left", outbox.messages.len());
+    }
+    result
+}
+
+/// Publishes a buffered event, returns whether it was published or dropped as it expired meanwhile.
+async fn publish_message(message: &OutboxMessage, policy: &PublishPolicy) -> Result<bool, ApplicationError> {
+    let mut properties = message.properties.clone();
+    if let Some(message_expiry) = properties.message_expiry {
+        // The broker counts the expiry from when it receives the event, so leave out the time it was buffered.
+        let buffered_for = DateTime::parse_from_rfc3339(&message.buffered_at)
+            .ok()
+            .and_then(|buffered_at| (Utc::now() - buffered_at.to_utc()).to_std().ok())
+            .unwrap_or_default();
+        let Some(message_expiry) = message_expiry.checked_sub(buffered_for).filter(|expiry| !expiry.is_zero()) else {
+            warn!("Dropping buffered event for topic {}, it expired while buffered", message.topic);
+            return Ok(false);
+        };
+        properties.message_expiry = Some(message_expiry);
+    }
+    let payload = codec::encode_payload(MessageClass::Event, message.payload.clone(), &mut properties)?;
+    get_mqtt_client().publish(&message.topic, &payload, policy.qos, policy.retain, &properties).await.with_whatever_context(|_| "Could not publish buffered event")?;
+    Ok(true)
+}
+
+fn pop_front() -> Option<OutboxMessage> {
+    let mut outbox = get_outbox().lock().unwrap();
+    let message = outbox.messages.pop_front()?;
+    outbox.size -= message.payload.len();
+    Some(message)
+}
+
+fn push_front(message: OutboxMessage) {
+    let mut outbox = get_outbox().lock().unwrap();
+    outbox.size += message.payload.len();
+    outbox.messages.push_front(message);
+}
+
+async fn publish_outbox_status() -> Result<(), ApplicationError> {
+    let status = {
+        let outbox = get_outbox().lock().unwrap();
+        MqttOutboxStatus {
+            queued: outbox.messages.len(),
+            dropped: outbox.dropped,
+            replayed: outbox.replayed,
+        }
+    };
+
+    let topic = topics::build_topic(&get_settings().mqtt.topics.outbox, None, None);
+    let payload = serde_json::to_string(&status).with_whatever_context(|_| format!("Could not serialize outbox status message for topic {topic}"))?;
+    publish_status(&topic, payload, None).await
+}
+
+// /////////// //
+// Persistence //
+// /////////// //
+
+/// Reads the events buffered before a restart, one JSON object per line.
+fn load_outbox() -> Outbox {
+    let mut outbox = Outbox::default();
+    let Some(file) = &get_settings().mqtt.outbox.file else {
+        return outbox;
+    };
+
+    match fs::read_to_string(file) {
+        Ok(contents) => {
+            for line in contents.lines().filter(|line| !line.is_empty()) {
+                match serde_json::from_str::<OutboxMessage>(line) {
+                    Ok(message) => {
+                        outbox.size += message.payload.len();
+                        outbox.messages.push_back(message);
+                    },
+                    Err(e) => warn!("Skipping unreadable event in outbox file {}: {e}", file.display()),
+                }
+            }
+        },
+        Err(e) if e.kind() == ErrorKind::NotFound => {},
+        Err(e) => error!("Could not read outbox file {}: {e}", file.display()),
+    }
+
+    // The limits may have been lowered since.
+    drop_oldest(&mut outbox);
+    if !outbox.messages.is_empty() {
+        info!("Loaded {} buffered event(s) from {}", outbox.messages.len(), file.display());
+    }
+    outbox
+}
+
+/// Rewrites the outbox file, if configured. The outbox is small and only changes while the broker can't be reached, so
+/// there's no need for anything smarter. A failure is logged, the events are still buffered in memory.
+fn save_outbox(outbox: &Outbox) {
+    let Some(file) = &get_settings().mqtt.outbox.file else {
+        return;
+    };
+
+    let contents = outbox.messages.iter()
+        .filter_map(|message| serde_json::to_string(message).ok())
+        .map(|line| line + "\n")
+        .collect::<String>();
+    // Written next to it first, so a crash halfway doesn't leave a truncated file.
+    let temp_file = file.with_extension("tmp");
+    if let Err(e) = fs::write(&temp_file, contents).and_then(|_| fs::rename(&temp_file, file)) {
+        error!("Could not write outbox file {}: {e}", file.display());
+    }
+}
@@ -0,0 +1,231 @@
+use ipp::model::PrinterState;
+use log::{debug, error, info, warn};
+use snafu::ResultExt;
+
+use crate::{
+    config::models::{ProvisionedQueue, ProvisioningMode},
+    cups_client::{self, models::{IppPrintQueueState, IppPrinterSettings}},
+    get_settings,
+    mqtt_client::models::{MqttQueueProvisioningDrift, MqttQueueProvisioningReport, MqttQueueProvisioningState, MqttQueueProvisioningStatus},
+    publish,
+    topics::{self, MessageClass},
+    ApplicationError,
+};
+
+// ////////////// //
+// Reconciliation //
+// ////////////// //
+
+/// Compares the configured queues with the queues known to CUPS, creates or updates them when
+/// provisioning is enforced and publishes the outcome to `<root_topic>/cups_server/provisioning`.
+pub async fn reconcile_print_queues(print_queues: &[IppPrintQueueState]) -> Result<(), ApplicationError> {
+    let settings = get_settings();
+
+    let mut statuses = Vec::<MqttQueueProvisioningStatus>::new();
+    for desired_queue in &settings.provisioning.queues {
+        // CUPS treats queue names case-insensitively, so `Office` in the configuration is the existing `office` queue.
+        let actual_queue = print_queues.iter().find(|q| q.queue_name.eq_ignore_ascii_case(&desired_queue.name));
+        let drift = match actual_queue {
+            Some(actual_queue) => find_drift(desired_queue, actual_queue),
+            None => Vec::new(),
+        };
+
+        let status = match actual_queue {
+            None => MqttQueueProvisioningState::Missing,
+            Some(_) if drift.is_empty() => MqttQueueProvisioningState::InSync,
+            Some(_) => MqttQueueProvisioningState::Drifted,
+        };
+
+        let (status, error) = match status {
+            MqttQueueProvisioningState::InSync => {
+                debug!("Queue [{}] matches the configuration", desired_queue.name);
+                (status, None)
+            },
+            _ if settings.provisioning.mode == ProvisioningMode::Enforce => {
+                let is_new = actual_queue.is_none();
+                match apply_queue_settings(desired_queue, is_new).await {
+                    Ok(()) if is_new => {
+                        info!("Created queue [{}]", desired_queue.name);
+                        (MqttQueueProvisioningState::Created, None)
+                    },
+                    Ok(()) => {
+                        info!("Updated queue [{}] to match the configuration", desired_queue.name);
+                        (MqttQueueProvisioningState::Updated, None)
+                    },
+                    Err(e) => {
+                        error!("Failed to provision queue [{}]: {e}", desired_queue.name);
+                        (MqttQueueProvisioningState::Failed, Some(e.to_string()))
+                    },
+                }
+            },
+            _ => {
+                warn!("Queue [{}] does not match the configuration ({:?})", desired_queue.name, status);
+                (status, None)
+            },
+        };
+
+        statuses.push(MqttQueueProvisioningStatus { name: desired_queue.name.clone(), status, drift, error });
+    }
+
+    let topic = topics::build_topic(&settings.mqtt.topics.provisioning, None, None);
+    let payload = serde_json::to_string(&MqttQueueProvisioningReport { queues: statuses })
+        .with_whatever_context(|_| format!("Could not serialize queue provisioning report for topic {topic}"))?;
+    publish(MessageClass::Status, &topic, payload).await
+}
+
+async fn apply_queue_settings(desired_queue: &ProvisionedQueue, is_new: bool) -> Result<(), ApplicationError> {
+    let settings = get_settings();
+    let queue_uri = cups_client::client::build_cups_url(&settings.cups, Some(&desired_queue.name)).with_whatever_context(|_| "Could not build CUPS URL")?;
+
+    let printer_settings = IppPrinterSettings {
+        device_uri: Some(desired_queue.device_uri.clone()),
+        // Changing the driver of an existing queue would throw away its PPD options, so only do so on creation.
+        ppd_name: if is_new { desired_queue.ppd_name.clone() } else { None },
+        description: desired_queue.description.clone(),
+        location: desired_queue.location.clone(),
+        is_shared: desired_queue.shared,
+        // Like `lpadmin -E`, new queues are enabled and accept jobs unless configured otherwise.
+        is_accepting_jobs: desired_queue.accepting.or(is_new.then_some(true)),
+        state: is_new.then_some(PrinterState::Idle),
+        defaults: desired_queue.defaults.clone(),
+    };
+
+    cups_client::client::add_modify_printer(queue_uri, &settings.cups.tls_options(), &printer_settings).await
+        .with_whatever_context(|_| format!("Could not add or modify queue {}", desired_queue.name))
+}
+
+// /////// //
+// Helpers //
+// /////// //
+
+fn find_drift(desired_queue: &ProvisionedQueue, actual_queue: &IppPrintQueueState) -> Vec<MqttQueueProvisioningDrift> {
+    let mut drift = Vec::<MqttQueueProvisioningDrift>::new();
+    let mut compare = |attribute: &str, expected: String, actual: Option<String>| {
+        if actual.as_ref() != Some(&expected) {
+            drift.push(MqttQueueProvisioningDrift { attribute: attribute.to_owned(), expected, actual });
+        }
+    };
+
+    compare("device-uri", desired_queue.device_uri.clone(), actual_queue.device_uri.clone());
+    if let Some(description) = &desired_queue.description {
+        compare("printer-info", description.clone(), Some(actual_queue.description.clone()));
+    }
+    if let Some(location) = &desired_queue.location {
+        compare("printer-location", location.clone(), Some(actual_queue.location.clone()));
+    }
+    if let Some(shared) = desired_queue.shared {
+        compare("printer-is-shared", shared.to_string(), Some(actual_queue.is_shared.to_string()));
+    }
+    if let Some(accepting) = desired_queue.accepting {
+        compare("printer-is-accepting-jobs", accepting.to_string(), Some(actual_queue.is_accepting_jobs.to_string()));
+    }
+    for (name, value) in &desired_queue.defaults {
+        compare(&format!("{name}-default"), value.clone(), actual_queue.defaults.get(name).cloned());
+    }
+
+    drift
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    fn build_desired_queue() -> ProvisionedQueue {
+        ProvisionedQueue {
+            name: "dnp".to_owned(),
+            device_uri: "gutenprint53+usb://dnp-ds620/1".to_owned(),
+            ppd_name: None,
+            description: Some("DNP DS620".to_owned()),
+            location: Some("Booth 1".to_owned()),
+            shared: Some(false),
+            accepting: Some(true),
+            defaults: BTreeMap::from([("media".to_owned(), "w288h432".to_owned())]),
+        }
+    }
+
+    fn build_actual_queue() -> IppPrintQueueState {
+        IppPrintQueueState {
+            queue_name: "dnp".to_owned(),
+            description: "DNP DS620".to_owned(),
+            location: "Booth 1".to_owned(),
+            printer_make: String::new(),
+            device_uri: Some("gutenprint53+usb://dnp-ds620/1".to_owned()),
+            state: PrinterState::Idle,
+            is_accepting_jobs: true,
+            is_shared: false,
+            job_count: 0,
+            state_message: String::new(),
+            state_reason: "none".to_owned(),
+            cups_version: String::new(),
+            markers: Vec::new(),
+            defaults: BTreeMap::from([("media".to_owned(), "w288h432".to_owned())]),
+        }
+    }
+
+    fn find_drift_of(actual_queue: &IppPrintQueueState) -> Vec<(String, String, Option<String>)> {
+        find_drift(&build_desired_queue(), actual_queue).into_iter()
+            .map(|drift| (drift.attribute, drift.expected, drift.actual))
+            .collect()
+    }
+
+    #[test]
+    fn finds_no_drift_in_matching_queue() {
+        assert!(find_drift_of(&build_actual_queue()).is_empty());
+    }
+
+    #[test]
+    fn finds_drift_of_device_uri() {
+        let mut actual_queue = build_actual_queue();
+        actual_queue.device_uri = Some("usb://DNP/DS620".to_owned());
+        assert_eq!(find_drift_of(&actual_queue), [("device-uri".to_owned(), "gutenprint53+usb://dnp-ds620/1".to_owned(), Some("usb://DNP/DS620".to_owned()))]);
+
+        actual_queue.device_uri = None;
+        assert_eq!(find_drift_of(&actual_queue), [("device-uri".to_owned(), "gutenprint53+usb://dnp-ds620/1".to_owned(), None)]);
+    }
+
+    #[test]
+    fn finds_drift_of_description_and_location() {
+        let mut actual_queue = build_actual_queue();
+        actual_queue.description = "Old printer".to_owned();
+        actual_queue.location = String::new();
+        assert_eq!(find_drift_of(&actual_queue), [
+            ("printer-info".to_owned(), "DNP DS620".to_owned(), Some("Old printer".to_owned())),
+            ("printer-location".to_owned(), "Booth 1".to_owned(), Some(String::new())),
+        ]);
+    }
+
+    #[test]
+    fn finds_drift_of_shared_and_accepting() {
+        let mut actual_queue = build_actual_queue();
+        actual_queue.is_shared = true;
+        actual_queue.is_accepting_jobs = false;
+        assert_eq!(find_drift_of(&actual_queue), [
+            ("printer-is-shared".to_owned(), "false".to_owned(), Some("true".to_owned())),
+            ("printer-is-accepting-jobs".to_owned(), "true".to_owned(), Some("false".to_owned())),
+        ]);
+    }
+
+    #[test]
+    fn finds_drift_of_defaults() {
+        let mut actual_queue = build_actual_queue();
+        actual_queue.defaults.insert("media".to_owned(), "w288h288".to_owned());
+        assert_eq!(find_drift_of(&actual_queue), [("media-default".to_owned(), "w288h432".to_owned(), Some("w288h288".to_owned()))]);
+
+        actual_queue.defaults.clear();
+        assert_eq!(find_drift_of(&actual_queue), [("media-default".to_owned(), "w288h432".to_owned(), None)]);
+    }
+
+    #[test]
+    fn ignores_unset_attributes_and_unmanaged_defaults() {
+        let desired_queue = ProvisionedQueue { description: None, location: None, shared: None, accepting: None, defaults: BTreeMap::new(), ..build_desired_queue() };
+        let mut actual_queue = build_actual_queue();
+        actual_queue.description = "Old printer".to_owned();
+        actual_queue.location = String::new();
+        actual_queue.is_shared = true;
+        actual_queue.is_accepting_jobs = false;
+        actual_queue.defaults.insert("sides".to_owned(), "one-sided".to_owned());
+        assert!(find_drift(&desired_queue, &actual_queue).is_empty());
+    }
+}
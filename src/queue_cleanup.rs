@@ -0,0 +1,130 @@
+use std::sync::OnceLock;
+
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use log::{debug, info};
+use snafu::ResultExt;
+
+use crate::{
+    build_flat_queue_status,
+    build_ha_marker_object_id,
+    build_polled_at_topic,
+    cups_client::models::IppPrintQueueState,
+    get_last_published_mqtt_messages,
+    get_mqtt_client,
+    get_settings,
+    homie,
+    is_status_republished,
+    mqtt_client::{client::MqttMessageProperties, models::{MqttCupsPrintQueueStatus, MqttTimestamps}},
+    stuck_jobs,
+    timestamps,
+    topics::{self, MessageClass},
+    ApplicationError,
+    HA_MARKER_SENSOR_FIELDS,
+    HA_QUEUE_SENSOR_FIELDS,
+};
+
+/// Every queue seen since startup, with its last known state and the moment it went missing, if it did.
+fn get_known_queues() -> &'static DashMap<String, KnownQueue> {
+    static KNOWN_QUEUES: OnceLock<DashMap<String, KnownQueue>> = OnceLock::new();
+    KNOWN_QUEUES.get_or_init(DashMap::new)
+}
+
+struct KnownQueue {
+    /// Which topics have been published depends on the state, e.g. on the markers.
+    last_state: IppPrintQueueState,
+    missing_since: Option<DateTime<Utc>>,
+}
+
+// /////// //
+// Cleanup //
+// /////// //
+
+/// Clears the retained state and discovery messages of queues which have been gone from CUPS for longer than the grace period.
+pub async fn remove_disappeared_queues(print_queues: &[IppPrintQueueState]) -> Result<(), ApplicationError> {
+    let settings = get_settings();
+    let Some(grace_period) = settings.mqtt.removed_queue_grace_period else {
+        return Ok(());
+    };
+
+    let now = Utc::now();
+    for queue in print_queues {
+        get_known_queues().insert(queue.queue_name.clone(), KnownQueue { last_state: queue.clone(), missing_since: None });
+    }
+
+    let mut removed_queues = Vec::<String>::new();
+    for mut known_queue in get_known_queues().iter_mut() {
+        let (queue_name, known_queue) = known_queue.pair_mut();
+        if print_queues.iter().any(|q| &q.queue_name == queue_name) {
+            continue;
+        }
+
+        let missing_since = *known_queue.missing_since.get_or_insert_with(|| {
+            info!("Queue [{queue_name}] disappeared, removing it from MQTT in {}", humantime::Duration::from(grace_period.0));
+            now
+        });
+        if (now - missing_since).to_std().is_ok_and(|missing_for| missing_for >= grace_period.0) {
+            removed_queues.push(queue_name.clone());
+        }
+    }
+
+    for queue_name in removed_queues {
+        let Some((_, known_queue)) = get_known_queues().remove(&queue_name) else { continue };
+        // Its topics are those of the queue which took over its name.
+        if print_queues.iter().any(|q| topics::is_name_shared(&q.queue_name, &queue_name)) {
+            info!("Queue [{queue_name}] has been gone for too long, but another queue has the same topic name, Home Assistant ID or Homie node ID, so its topics are kept");
+            continue;
+        }
+        remove_queue_topics(&known_queue.last_state).await?;
+    }
+
+    Ok(())
+}
+
+async fn remove_queue_topics(queue: &IppPrintQueueState) -> Result<(), ApplicationError> {
+    let settings = get_settings();
+    let queue_name = &queue.queue_name;
+    let state_topic = topics::build_topic(&settings.mqtt.topics.queue_status, Some(queue_name), None);
+
+    let topics = build_queue_topics(queue, &state_topic)?;
+    for topic in &topics {
+        // An empty retained message removes the retained message from the broker.
+        get_mqtt_client().publish(topic, &[], MessageClass::Status.policy().qos, true, &MqttMessageProperties::default()).await.with_whatever_context(|_| "Could not publish to MQTT")?;
+        get_last_published_mqtt_messages().remove(topic);
+        debug!("Cleared topic {topic}");
+    }
+
+    timestamps::forget(&state_topic);
+
+    info!("Queue [{queue_name}] has been gone for too long, cleared its {} topic(s)", topics.len());
+    Ok(())
+}
+
+/// Every retained topic that is published for the queue with the current settings, whether or not it still has a
+/// message. The last published messages can't tell, as they are forgotten when everything is republished.
+fn build_queue_topics(queue: &IppPrintQueueState, state_topic: &str) -> Result<Vec<String>, ApplicationError> {
+    let settings = get_settings();
+    let queue_name = &queue.queue_name;
+
+    let mut queue_topics = topics::build_queue_topics(queue_name);
+    if settings.mqtt.flat_topics {
+        let timestamps = MqttTimestamps { polled_at: is_status_republished().then(String::new), ..MqttTimestamps::default() };
+        let status = MqttCupsPrintQueueStatus { timestamps: Some(timestamps), ..MqttCupsPrintQueueStatus::from(queue) };
+        queue_topics.extend(build_flat_queue_status(state_topic, &status)?.into_iter().map(|(topic, _)| topic));
+    }
+    if settings.mqtt.publish_polled_at && !queue_topics.contains(&build_polled_at_topic(state_topic)) {
+        queue_topics.push(build_polled_at_topic(state_topic));
+    }
+    if settings.mqtt.ha.enable_discovery {
+        let mut object_ids = HA_QUEUE_SENSOR_FIELDS.iter().map(|field| field.to_string()).collect::<Vec<_>>();
+        for i in 0..queue.markers.len() {
+            object_ids.extend(HA_MARKER_SENSOR_FIELDS.iter().map(|field| build_ha_marker_object_id(i, field)));
+        }
+        queue_topics.extend(object_ids.iter().map(|object_id| topics::build_ha_queue_discovery_topic("sensor", queue_name, object_id)));
+        queue_topics.push(topics::build_ha_queue_discovery_topic("binary_sensor", queue_name, stuck_jobs::HA_OBJECT_ID));
+    }
+    if settings.mqtt.homie.enabled {
+        queue_topics.extend(homie::build_queue_node_topics(queue));
+    }
+    Ok(queue_topics)
+}
@@ -0,0 +1,174 @@
+use std::sync::OnceLock;
+
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use ipp::model::{JobState, PrinterState};
+use log::{error, info, warn};
+use snafu::{whatever, ResultExt};
+
+use crate::{
+    config::models::RecoveryPolicy,
+    cups_client::{self, models::{IppJob, IppPrintQueueState}},
+    get_settings,
+    homie,
+    mqtt_client::models::{MqttAuditAction, MqttAuditEvent},
+    publish,
+    topics::{self, MessageClass},
+    ApplicationError,
+};
+
+struct RecoveryState {
+    last_attempt_at: DateTime<Utc>,
+    attempts: u32,
+}
+
+/// Recovery progress of each stopped queue with a recovery policy, keyed by queue name.
+fn get_recovery_states() -> &'static DashMap<String, RecoveryState> {
+    static RECOVERY_STATES: OnceLock<DashMap<String, RecoveryState>> = OnceLock::new();
+    RECOVERY_STATES.get_or_init(DashMap::new)
+}
+
+// ///////////// //
+// Policy engine //
+// ///////////// //
+
+/// Reports settings of the default policy which are set without `resume_after`, as recovery would quietly be off, and
+/// policies which would cancel jobs before retrying them.
+pub fn check_settings() -> Result<(), ApplicationError> {
+    let recovery = &get_settings().recovery;
+    if recovery.resume_after.is_none() && (recovery.retry_job_after_attempts.is_some() || recovery.cancel_job_after_attempts.is_some()) {
+        whatever!("recovery.retry_job_after_attempts or recovery.cancel_job_after_attempts is set, but recovery.resume_after isn't");
+    }
+
+    let policies = recovery.queues.iter().map(|(name, policy)| (name.as_str(), policy.clone()))
+        .chain(recovery.default_policy().map(|policy| ("default", policy)));
+    for (name, policy) in policies {
+        if let (Some(retry_after), Some(cancel_after)) = (policy.retry_job_after_attempts, policy.cancel_job_after_attempts) && retry_after >= cancel_after {
+            whatever!("The {name} recovery policy cancels jobs before retrying them, retry_job_after_attempts must be less than cancel_job_after_attempts");
+        }
+    }
+    Ok(())
+}
+
+/// Applies the configured recovery policies to the queues stopped by an error. Every action is logged
+/// and published to `<root_topic>/cups_server/audit`.
+pub async fn recover_stopped_queues(print_queues: &[IppPrintQueueState]) -> Result<(), ApplicationError> {
+    let settings = get_settings();
+
+    for queue in print_queues {
+        let Some(policy) = settings.recovery.policy_for_queue(&queue.queue_name) else {
+            continue;
+        };
+
+        if queue.state != PrinterState::Stopped {
+            if let Some((_, state)) = get_recovery_states().remove(&queue.queue_name) && state.attempts > 0 {
+                info!("Queue [{}] recovered after {} attempt(s)", queue.queue_name, state.attempts);
+                publish_audit_event(&queue.queue_name, MqttAuditAction::Recovered, Some(state.attempts), None, Ok(())).await?;
+            }
+            continue;
+        }
+        if !is_stopped_by_error(queue) {
+            // Paused on purpose, or stopped without an error to recover from, so it stays stopped until someone resumes it.
+            get_recovery_states().remove(&queue.queue_name);
+            continue;
+        }
+
+        let now = Utc::now();
+        let attempt = {
+            let mut state = get_recovery_states().entry(queue.queue_name.clone()).or_insert_with(|| {
+                warn!("Queue [{}] stopped ({}), will try to recover it in {}", queue.queue_name, queue.state_message, humantime::Duration::from(policy.resume_after.0));
+                RecoveryState { last_attempt_at: now, attempts: 0 }
+            });
+            if (now - state.last_attempt_at).to_std().is_ok_and(|waited| waited < policy.resume_after.0) {
+                continue;
+            }
+            state.last_attempt_at = now;
+            state.attempts += 1;
+            state.attempts
+        };
+
+        recover_queue(queue, &policy, attempt).await?;
+    }
+
+    Ok(())
+}
+
+async fn recover_queue(queue: &IppPrintQueueState, policy: &RecoveryPolicy, attempt: u32) -> Result<(), ApplicationError> {
+    let settings = get_settings();
+    let queue_uri = cups_client::client::build_cups_url(&settings.cups, Some(&queue.queue_name)).with_whatever_context(|_| "Could not build CUPS URL")?;
+
+    // Resuming alone didn't help enough times, so the current job is probably the problem.
+    if policy.cancel_job_after_attempts.is_some_and(|max_attempts| attempt > max_attempts) {
+        let current_job = get_current_job(&queue_uri, &queue.queue_name, &[JobState::Processing, JobState::ProcessingStopped, JobState::Pending]).await?;
+        if let Some(current_job) = current_job {
+            let result = cups_client::client::cancel_job(queue_uri.clone(), &settings.cups.tls_options(), current_job.job_id).await;
+            match &result {
+                Ok(_) => warn!("Canceled job {} on queue [{}] after {} failed resume attempt(s)", current_job.job_id, queue.queue_name, attempt - 1),
+                Err(e) => error!("Failed to cancel job {} on queue [{}]: {e}", current_job.job_id, queue.queue_name),
+            }
+            publish_audit_event(&queue.queue_name, MqttAuditAction::CancelJob, Some(attempt), Some(current_job.job_id), result.map_err(|e| e.to_string())).await?;
+        }
+
+        // Start counting again for the next job.
+        if let Some(mut state) = get_recovery_states().get_mut(&queue.queue_name) {
+            state.attempts = 0;
+        }
+    } else if policy.retry_job_after_attempts.is_some_and(|max_attempts| attempt == max_attempts + 1) {
+        // Before giving up on the job, print it again. A pending job is retried by resuming the queue anyway.
+        let current_job = get_current_job(&queue_uri, &queue.queue_name, &[JobState::ProcessingStopped, JobState::PendingHeld]).await?;
+        if let Some(current_job) = current_job {
+            let result = match current_job.state {
+                JobState::PendingHeld => cups_client::client::release_job(queue_uri.clone(), &settings.cups.tls_options(), current_job.job_id).await,
+                _ => cups_client::client::restart_job(queue_uri.clone(), &settings.cups.tls_options(), current_job.job_id).await,
+            };
+            match &result {
+                Ok(_) => warn!("Retried job {} on queue [{}] after {} failed resume attempt(s)", current_job.job_id, queue.queue_name, attempt - 1),
+                Err(e) => error!("Failed to retry job {} on queue [{}]: {e}", current_job.job_id, queue.queue_name),
+            }
+            publish_audit_event(&queue.queue_name, MqttAuditAction::RetryJob, Some(attempt), Some(current_job.job_id), result.map_err(|e| e.to_string())).await?;
+        }
+    }
+
+    let result = cups_client::client::resume_printer(queue_uri, &settings.cups.tls_options()).await;
+    match &result {
+        Ok(_) => info!("Resumed queue [{}] (attempt {attempt})", queue.queue_name),
+        Err(e) => error!("Failed to resume queue [{}]: {e}", queue.queue_name),
+    }
+    publish_audit_event(&queue.queue_name, MqttAuditAction::ResumePrinter, Some(attempt), None, result.map_err(|e| e.to_string())).await
+}
+
+// /////// //
+// Helpers //
+// /////// //
+
+/// Whether CUPS stopped the queue because of a backend or device error, rather than someone pausing it on purpose.
+fn is_stopped_by_error(queue: &IppPrintQueueState) -> bool {
+    queue.state == PrinterState::Stopped
+        && !homie::is_paused_on_purpose(queue)
+        && (!queue.state_reasons().is_empty() || !queue.state_message.is_empty())
+}
+
+/// The job in one of `states` which the queue is printing, or otherwise the next one it would print.
+async fn get_current_job(queue_uri: &str, queue_name: &str, states: &[JobState]) -> Result<Option<IppJob>, ApplicationError> {
+    let settings = get_settings();
+    let jobs = cups_client::client::get_jobs(queue_uri.to_owned(), &settings.cups.tls_options()).await.with_whatever_context(|_| format!("Could not get jobs of queue {queue_name}"))?;
+    Ok(jobs.into_iter()
+        .filter(|job| states.contains(&job.state))
+        .min_by_key(|job| (matches!(job.state, JobState::Pending | JobState::PendingHeld), job.job_id)))
+}
+
+async fn publish_audit_event(queue_name: &str, action: MqttAuditAction, attempt: Option<u32>, job_id: Option<i32>, result: Result<(), String>) -> Result<(), ApplicationError> {
+    let settings = get_settings();
+
+    let topic = topics::build_topic(&settings.mqtt.topics.audit, None, None);
+    let payload = serde_json::to_string(&MqttAuditEvent {
+        timestamp: Utc::now().to_rfc3339(),
+        queue: queue_name.to_owned(),
+        action,
+        attempt,
+        job_id,
+        is_success: result.is_ok(),
+        error: result.err(),
+    }).with_whatever_context(|_| format!("Could not serialize audit event for topic {topic}"))?;
+    publish(MessageClass::Event, &topic, payload).await
+}
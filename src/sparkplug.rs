@@ -0,0 +1,309 @@
+use std::{collections::BTreeMap, sync::{atomic::{AtomicU64, Ordering}, OnceLock}};
+
+use chrono::Utc;
+use ipp::model::PrinterState;
+use prost::Message;
+use snafu::ResultExt;
+use tokio::sync::Mutex;
+
+use crate::{
+    config::models::MqttQos,
+    cups_client::{client::CupsError, models::IppPrintQueueState},
+    get_mqtt_client,
+    get_settings,
+    mqtt_client::{
+        client::{MqttLastWill, MqttMessageProperties},
+        models::{SparkplugDataType, SparkplugMetric, SparkplugMetricValue, SparkplugPayload},
+    },
+    topics,
+    ApplicationError,
+};
+
+const METRIC_BD_SEQ: &str = "bdSeq";
+const METRIC_REBIRTH: &str = "Node Control/Rebirth";
+
+/// Metrics by name, without timestamp, as last published.
+type Metrics = BTreeMap<String, SparkplugMetric>;
+
+#[derive(Debug, Default)]
+struct EdgeNode {
+    /// The connection in which the NBIRTH was published, births are needed again after reconnecting.
+    born_in_connection: Option<u64>,
+    seq: u64,
+    metrics: Metrics,
+    /// Keyed by device ID.
+    devices: BTreeMap<String, Metrics>,
+}
+
+impl EdgeNode {
+    /// Every message but NDEATH is numbered, from 0 for the NBIRTH up to 255 and starting over.
+    fn next_seq(&mut self) -> u64 {
+        let seq = self.seq;
+        self.seq = (self.seq + 1) % 256;
+        seq
+    }
+}
+
+fn get_edge_node() -> &'static Mutex<EdgeNode> {
+    static EDGE_NODE: OnceLock<Mutex<EdgeNode>> = OnceLock::new();
+    EDGE_NODE.get_or_init(|| Mutex::new(EdgeNode::default()))
+}
+
+/// Connections made to the broker, counting attempts. The bdSeq of each connection is derived from it.
+static CONNECTION_COUNT: AtomicU64 = AtomicU64::new(0);
+
+// ///////// //
+// Last will //
+// ///////// //
+
+/// Builds the NDEATH last will for a new connection. Its bdSeq is increased on every connection, so hosts can tell a
+/// late NDEATH of an earlier connection from that of the current one.
+pub fn build_death_certificate() -> MqttLastWill {
+    CONNECTION_COUNT.fetch_add(1, Ordering::SeqCst);
+    MqttLastWill {
+        topic: get_settings().mqtt.sparkplug.topic("NDEATH", None),
+        payload: build_death_payload().encode_to_vec(),
+        retain: false,
+    }
+}
+
+/// The bdSeq of the current connection, matching the one in its NDEATH last will.
+fn get_bd_seq() -> u64 {
+    CONNECTION_COUNT.load(Ordering::SeqCst).saturating_sub(1) % 256
+}
+
+fn build_death_payload() -> SparkplugPayload {
+    SparkplugPayload {
+        timestamp: Some(now()),
+        metrics: vec![build_bd_seq_metric()],
+        seq: None,
+    }
+}
+
+/// The bdSeq metric of both the NBIRTH and the NDEATH, which have to match.
+fn build_bd_seq_metric() -> SparkplugMetric {
+    build_metric(METRIC_BD_SEQ, SparkplugDataType::UInt64, Some(SparkplugMetricValue::Long(get_bd_seq())))
+}
+
+// ////////// //
+// Publishing //
+// ////////// //
+
+/// Publishes the CUPS server as Sparkplug edge node, with a device for every print queue. Births are published after
+/// connecting, changed metrics afterwards. When CUPS can't be reached, all devices die until it can be reached again.
+pub async fn publish_edge_node(print_queues_result: &Result<Vec<IppPrintQueueState>, CupsError>) -> Result<(), ApplicationError> {
+    // Messages queued while disconnected would be out of sequence for the next connection, which starts with births.
+    if !get_mqtt_client().is_connected() {
+        return Ok(());
+    }
+
+    let mut edge_node = get_edge_node().lock().await;
+    let connection = CONNECTION_COUNT.load(Ordering::SeqCst);
+    let metrics = build_node_metrics(print_queues_result);
+    let devices = match print_queues_result {
+        Ok(print_queues) => print_queues.iter()
+            .filter(|queue| !topics::is_colliding(&queue.queue_name))
+            .map(|queue| (topics::get_queue_topic_name(&queue.queue_name), build_device_metrics(queue)))
+            .collect(),
+        Err(_) => BTreeMap::new(),
+    };
+
+    if edge_node.born_in_connection != Some(connection) {
+        edge_node.seq = 0;
+        edge_node.devices.clear();
+        let mut birth_metrics = vec![
+            build_bd_seq_metric(),
+            build_metric(METRIC_REBIRTH, SparkplugDataType::Boolean, Some(SparkplugMetricValue::Boolean(false))),
+        ];
+        birth_metrics.extend(metrics.values().cloned());
+        publish_message(&mut edge_node, "NBIRTH", None, birth_metrics).await?;
+        edge_node.born_in_connection = Some(connection);
+    } else {
+        let changed_metrics = get_changed_metrics(&edge_node.metrics, &metrics);
+        if !changed_metrics.is_empty() {
+            publish_message(&mut edge_node, "NDATA", None, changed_metrics).await?;
+        }
+    }
+    edge_node.metrics = metrics;
+
+    let dead_devices = edge_node.devices.keys().filter(|device_id| !devices.contains_key(*device_id)).cloned().collect::<Vec<_>>();
+    for device_id in dead_devices {
+        publish_message(&mut edge_node, "DDEATH", Some(&device_id), Vec::new()).await?;
+        edge_node.devices.remove(&device_id);
+    }
+    for (device_id, metrics) in devices {
+        match edge_node.devices.get(&device_id) {
+            // Metrics can only be added in a birth, e.g. when a marker appears.
+            Some(last_metrics) if last_metrics.keys().eq(metrics.keys()) => {
+                let changed_metrics = get_changed_metrics(last_metrics, &metrics);
+                if !changed_metrics.is_empty() {
+                    publish_message(&mut edge_node, "DDATA", Some(&device_id), changed_metrics).await?;
+                }
+            },
+            _ => publish_message(&mut edge_node, "DBIRTH", Some(&device_id), metrics.values().cloned().collect()).await?,
+        }
+        edge_node.devices.insert(device_id, metrics);
+    }
+    Ok(())
+}
+
+/// Publishes the NDEATH for when CUPS2MQTT stops, as the broker only publishes the last will when the connection is lost.
+pub async fn publish_death() -> Result<(), ApplicationError> {
+    let topic = get_settings().mqtt.sparkplug.topic("NDEATH", None);
+    publish(&topic, build_death_payload()).await?;
+    get_edge_node().lock().await.born_in_connection = None;
+    Ok(())
+}
+
+async fn publish_message(edge_node: &mut EdgeNode, message_type: &str, device_id: Option<&str>, metrics: Vec<SparkplugMetric>) -> Result<(), ApplicationError> {
+    let timestamp = now();
+    let payload = SparkplugPayload {
+        timestamp: Some(timestamp),
+        metrics: metrics.into_iter().map(|metric| SparkplugMetric { timestamp: Some(timestamp), ..metric }).collect(),
+        seq: Some(edge_node.next_seq()),
+    };
+    publish(&get_settings().mqtt.sparkplug.topic(message_type, device_id), payload).await
+}
+
+/// Sparkplug messages are never retained, and births and data are published with QoS 0.
+async fn publish(topic: &str, payload: SparkplugPayload) -> Result<(), ApplicationError> {
+    get_mqtt_client().publish(topic, &payload.encode_to_vec(), MqttQos::AtMostOnce, false, &MqttMessageProperties::default()).await
+        .with_whatever_context(|_| "Could not publish to MQTT")
+}
+
+fn build_node_metrics(print_queues_result: &Result<Vec<IppPrintQueueState>, CupsError>) -> Metrics {
+    let cups_version = match print_queues_result {
+        Ok(print_queues) => print_queues.first().map(|q| q.cups_version.clone()),
+        Err(_) => None,
+    };
+    build_metrics(vec![
+        build_metric("Reachable", SparkplugDataType::Boolean, Some(SparkplugMetricValue::Boolean(print_queues_result.is_ok()))),
+        build_metric("Properties/CUPS version", SparkplugDataType::String, cups_version.map(SparkplugMetricValue::String)),
+        build_metric("Properties/CUPS2MQTT version", SparkplugDataType::String, Some(SparkplugMetricValue::String(env!("CARGO_PKG_VERSION").to_owned()))),
+    ])
+}
+
+fn build_device_metrics(queue: &IppPrintQueueState) -> Metrics {
+    let state = match queue.state {
+        PrinterState::Idle => "idle",
+        PrinterState::Processing => "processing",
+        PrinterState::Stopped => "stopped",
+    };
+    let mut metrics = vec![
+        build_metric("Description", SparkplugDataType::String, Some(SparkplugMetricValue::String(queue.description.clone()))),
+        build_metric("State", SparkplugDataType::String, Some(SparkplugMetricValue::String(state.to_owned()))),
+        build_metric("Job count", SparkplugDataType::Int32, Some(SparkplugMetricValue::Int(queue.job_count as u32))),
+        build_metric("State message", SparkplugDataType::String, Some(SparkplugMetricValue::String(queue.state_message.clone()))),
+        build_metric("State reason", SparkplugDataType::String, Some(SparkplugMetricValue::String(queue.state_reason.clone()))),
+    ];
+    for marker in &queue.markers {
+        metrics.push(build_metric(&format!("Markers/{}/Level", marker.name), SparkplugDataType::Int32, marker.level.map(SparkplugMetricValue::Int)));
+    }
+    build_metrics(metrics)
+}
+
+fn build_metrics(metrics: Vec<SparkplugMetric>) -> Metrics {
+    metrics.into_iter().map(|metric| (metric.name.clone().unwrap_or_default(), metric)).collect()
+}
+
+/// Builds a metric, `None` being a null value.
+fn build_metric(name: &str, datatype: SparkplugDataType, value: Option<SparkplugMetricValue>) -> SparkplugMetric {
+    SparkplugMetric {
+        name: Some(name.to_owned()),
+        timestamp: None,
+        datatype: Some(datatype as u32),
+        is_null: value.is_none().then_some(true),
+        value,
+    }
+}
+
+fn get_changed_metrics(last_metrics: &Metrics, metrics: &Metrics) -> Vec<SparkplugMetric> {
+    metrics.iter()
+        .filter(|(name, metric)| last_metrics.get(*name) != Some(*metric))
+        .map(|(_, metric)| metric.clone())
+        .collect()
+}
+
+fn now() -> u64 {
+    Utc::now().timestamp_millis() as u64
+}
+
+// //////// //
+// Commands //
+// //////// //
+
+/// Topic of the commands for the edge node.
+pub fn build_command_topic() -> String {
+    get_settings().mqtt.sparkplug.topic("NCMD", None)
+}
+
+/// Whether an NCMD message asks for a rebirth, which is the only command supported.
+pub fn is_rebirth_requested(payload: &[u8]) -> bool {
+    SparkplugPayload::decode(payload).is_ok_and(|payload| payload.metrics.iter().any(|metric| {
+        metric.name.as_deref() == Some(METRIC_REBIRTH) && metric.value == Some(SparkplugMetricValue::Boolean(true))
+    }))
+}
+
+/// Makes the next run publish all births again.
+pub async fn request_rebirth() {
+    get_edge_node().lock().await.born_in_connection = None;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_bd_seq(payload: &[u8]) -> Option<SparkplugMetricValue> {
+        let payload = SparkplugPayload::decode(payload).unwrap();
+        payload.metrics.into_iter().find(|metric| metric.name.as_deref() == Some(METRIC_BD_SEQ)).and_then(|metric| metric.value)
+    }
+
+    #[test]
+    fn wraps_seq_at_256() {
+        let mut edge_node = EdgeNode::default();
+        let seqs = (0..258).map(|_| edge_node.next_seq()).collect::<Vec<_>>();
+
+        assert_eq!(seqs[..3], [0, 1, 2]);
+        assert_eq!(seqs[255..], [255, 0, 1]);
+    }
+
+    #[test]
+    fn matches_bd_seq_of_death_and_birth() {
+        // The only test using the connection count, as the tests share it.
+        CONNECTION_COUNT.store(0, Ordering::SeqCst);
+        let death_certificate = build_death_certificate();
+        assert_eq!(decode_bd_seq(&death_certificate.payload), Some(SparkplugMetricValue::Long(0)));
+        assert_eq!(build_bd_seq_metric().value, Some(SparkplugMetricValue::Long(0)));
+
+        let death_certificate = build_death_certificate();
+        assert_eq!(decode_bd_seq(&death_certificate.payload), Some(SparkplugMetricValue::Long(1)));
+        assert_eq!(build_bd_seq_metric().value, Some(SparkplugMetricValue::Long(1)));
+        assert_eq!(decode_bd_seq(&build_death_payload().encode_to_vec()), Some(SparkplugMetricValue::Long(1)));
+
+        // Wraps like seq.
+        CONNECTION_COUNT.store(256, Ordering::SeqCst);
+        let death_certificate = build_death_certificate();
+        assert_eq!(decode_bd_seq(&death_certificate.payload), Some(SparkplugMetricValue::Long(0)));
+        assert_eq!(build_bd_seq_metric().value, Some(SparkplugMetricValue::Long(0)));
+    }
+
+    #[test]
+    fn gets_changed_metrics() {
+        let last_metrics = build_metrics(vec![
+            build_metric("State", SparkplugDataType::String, Some(SparkplugMetricValue::String("idle".to_owned()))),
+            build_metric("Job count", SparkplugDataType::Int32, Some(SparkplugMetricValue::Int(0))),
+            build_metric("Markers/Ribbon/Level", SparkplugDataType::Int32, Some(SparkplugMetricValue::Int(42))),
+        ]);
+        let metrics = build_metrics(vec![
+            build_metric("State", SparkplugDataType::String, Some(SparkplugMetricValue::String("processing".to_owned()))),
+            build_metric("Job count", SparkplugDataType::Int32, Some(SparkplugMetricValue::Int(0))),
+            build_metric("Markers/Ribbon/Level", SparkplugDataType::Int32, None),
+        ]);
+
+        let changed_metrics = get_changed_metrics(&last_metrics, &metrics);
+        let changed_names = changed_metrics.iter().map(|metric| metric.name.as_deref().unwrap()).collect::<Vec<_>>();
+        assert_eq!(changed_names, ["Markers/Ribbon/Level", "State"]);
+        assert_eq!(changed_metrics[0].is_null, Some(true));
+        assert!(get_changed_metrics(&metrics, &metrics).is_empty());
+    }
+}
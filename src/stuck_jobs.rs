@@ -0,0 +1,225 @@
+use std::{collections::HashSet, sync::OnceLock, time::Duration};
+
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use log::{info, warn};
+use snafu::ResultExt;
+
+use crate::{
+    build_ha_availability,
+    build_ha_queue_device,
+    cups_client::{self, models::IppPrintQueueState},
+    get_settings,
+    mqtt_client::models::{HomeAssistantDiscoveryBinarySensorPayload, MqttCupsJobState, MqttCupsJobStatus, MqttCupsStuckJob, MqttCupsStuckJobs},
+    publish,
+    topics::{self, MessageClass},
+    ApplicationError,
+};
+
+struct JobProgress {
+    state: MqttCupsJobState,
+    impressions_completed: Option<i32>,
+    state_message: Option<String>,
+    since: DateTime<Utc>,
+    is_stuck: bool,
+}
+
+impl JobProgress {
+    fn new(status: &MqttCupsJobStatus, now: DateTime<Utc>) -> Self {
+        Self {
+            state: status.state,
+            impressions_completed: status.impressions_completed,
+            state_message: status.state_message.clone(),
+            since: now,
+            is_stuck: false,
+        }
+    }
+
+    /// Records the status seen at `now`, any progress (a change of state, state message or completed impressions)
+    /// restarts the wait. Returns whether the job is processing or held without progress for at least `threshold`.
+    fn update(&mut self, status: &MqttCupsJobStatus, now: DateTime<Utc>, threshold: Duration) -> bool {
+        if self.state != status.state || self.impressions_completed != status.impressions_completed || self.state_message != status.state_message {
+            *self = Self { is_stuck: self.is_stuck, ..Self::new(status, now) };
+        }
+
+        let is_waiting = matches!(status.state, MqttCupsJobState::Processing | MqttCupsJobState::PendingHeld);
+        is_waiting && (now - self.since).to_std().is_ok_and(|waiting_for| waiting_for >= threshold)
+    }
+}
+
+/// The last progress seen for each not yet completed job, keyed by queue name and job ID.
+fn get_job_progress() -> &'static DashMap<(String, i32), JobProgress> {
+    static JOB_PROGRESS: OnceLock<DashMap<(String, i32), JobProgress>> = OnceLock::new();
+    JOB_PROGRESS.get_or_init(DashMap::new)
+}
+
+// ///////// //
+// Detection //
+// ///////// //
+
+/// Publishes the jobs which are processing or held without progress (a change of state, state message or
+/// completed impressions) for longer than the configured threshold to `<root_topic>/<queue>/stuck_jobs`.
+pub async fn publish_stuck_jobs(print_queues: &[IppPrintQueueState]) -> Result<(), ApplicationError> {
+    let settings = get_settings();
+
+    for queue in print_queues {
+        let Some(threshold) = settings.stuck_jobs.threshold_for_queue(&queue.queue_name) else {
+            continue;
+        };
+
+        let jobs = match queue.job_count {
+            0 => Vec::new(),
+            _ => {
+                let queue_uri = cups_client::client::build_cups_url(&settings.cups, Some(&queue.queue_name)).with_whatever_context(|_| "Could not build CUPS URL")?;
+                cups_client::client::get_jobs(queue_uri, &settings.cups.tls_options()).await.with_whatever_context(|_| format!("Could not get jobs of queue {}", queue.queue_name))?
+            },
+        };
+
+        let now = Utc::now();
+        let mut stuck_jobs = Vec::<MqttCupsStuckJob>::new();
+        for job in &jobs {
+            let status = MqttCupsJobStatus::from_ipp_job(&queue.queue_name, job);
+            let mut progress = get_job_progress().entry((queue.queue_name.clone(), job.job_id)).or_insert_with(|| JobProgress::new(&status, now));
+            let is_stuck = progress.update(&status, now, threshold.0);
+            if is_stuck && !progress.is_stuck {
+                warn!("Job {} on queue [{}] is stuck, it has been {:?} without progress since {}", job.job_id, queue.queue_name, status.state, progress.since.to_rfc3339());
+            } else if !is_stuck && progress.is_stuck {
+                info!("Job {} on queue [{}] is no longer stuck", job.job_id, queue.queue_name);
+            }
+            progress.is_stuck = is_stuck;
+
+            if is_stuck {
+                stuck_jobs.push(MqttCupsStuckJob { stuck_since: progress.since.to_rfc3339(), job: status });
+            }
+        }
+
+        // Forget jobs of this queue which are completed or gone.
+        let job_ids = jobs.iter().map(|job| job.job_id).collect::<HashSet<_>>();
+        get_job_progress().retain(|(queue_name, job_id), _| queue_name != &queue.queue_name || job_ids.contains(job_id));
+
+        if topics::is_colliding(&queue.queue_name) {
+            continue;
+        }
+        let topic = topics::build_topic(&settings.mqtt.topics.stuck_jobs, Some(&queue.queue_name), None);
+        let payload = serde_json::to_string(&MqttCupsStuckJobs { is_stuck: !stuck_jobs.is_empty(), jobs: stuck_jobs })
+            .with_whatever_context(|_| format!("Could not serialize stuck jobs message for topic {topic}"))?;
+        publish(MessageClass::Status, &topic, payload).await?;
+
+        if settings.mqtt.ha.enable_discovery {
+            publish_ha_stuck_jobs_discovery_topic(queue).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Object ID of the Home Assistant binary sensor of a queue.
+pub const HA_OBJECT_ID: &str = "stuck_jobs";
+
+async fn publish_ha_stuck_jobs_discovery_topic(queue: &IppPrintQueueState) -> Result<(), ApplicationError> {
+    let settings = get_settings();
+
+    let queue_slug = topics::get_queue_slug(&queue.queue_name);
+    let topic = topics::build_ha_queue_discovery_topic("binary_sensor", &queue.queue_name, HA_OBJECT_ID);
+    let payload = serde_json::to_string(&HomeAssistantDiscoveryBinarySensorPayload {
+        name: "Stuck job".to_owned(),
+        state_topic: topics::build_topic(&settings.mqtt.topics.stuck_jobs, Some(&queue.queue_name), None),
+        unique_id: format!("{}_{HA_OBJECT_ID}_{}", queue_slug, settings.mqtt.ha.component_id),
+        value_template: "{{ 'ON' if value_json.is_stuck else 'OFF' }}".to_owned(),
+        device_class: Some("problem".to_owned()),
+        availability: build_ha_availability(),
+        device: build_ha_queue_device(queue),
+    }).with_whatever_context(|_| format!("Could not serialize HA stuck jobs discovery message for topic {topic}"))?;
+    publish(MessageClass::Discovery, &topic, payload).await
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeDelta;
+
+    use super::*;
+    use crate::config::duration::HumanDuration;
+    use crate::config::models::StuckJobs;
+
+    const THRESHOLD: Duration = Duration::from_secs(300);
+
+    fn build_status(state: MqttCupsJobState, impressions_completed: Option<i32>) -> MqttCupsJobStatus {
+        MqttCupsJobStatus {
+            job_id: 1,
+            queue: "dnp".to_owned(),
+            name: "photo".to_owned(),
+            state,
+            state_reasons: Vec::new(),
+            state_message: None,
+            impressions_completed,
+        }
+    }
+
+    #[test]
+    fn processing_job_is_stuck_from_threshold() {
+        let start = Utc::now();
+        let status = build_status(MqttCupsJobState::Processing, Some(0));
+        let mut progress = JobProgress::new(&status, start);
+
+        assert!(!progress.update(&status, start + TimeDelta::seconds(299), THRESHOLD));
+        assert!(progress.update(&status, start + TimeDelta::seconds(300), THRESHOLD));
+        assert!(progress.update(&status, start + TimeDelta::seconds(301), THRESHOLD));
+    }
+
+    #[test]
+    fn held_job_is_stuck_after_threshold() {
+        let start = Utc::now();
+        let status = build_status(MqttCupsJobState::PendingHeld, None);
+        let mut progress = JobProgress::new(&status, start);
+
+        assert!(progress.update(&status, start + TimeDelta::seconds(301), THRESHOLD));
+    }
+
+    #[test]
+    fn pending_job_is_never_stuck() {
+        let start = Utc::now();
+        let status = build_status(MqttCupsJobState::Pending, None);
+        let mut progress = JobProgress::new(&status, start);
+
+        assert!(!progress.update(&status, start + TimeDelta::hours(1), THRESHOLD));
+    }
+
+    #[test]
+    fn progressing_job_is_not_stuck() {
+        let start = Utc::now();
+        let mut progress = JobProgress::new(&build_status(MqttCupsJobState::Processing, Some(0)), start);
+
+        for (minutes, impressions_completed) in [(4, 1), (8, 2), (12, 3)] {
+            let now = start + TimeDelta::minutes(minutes);
+            assert!(!progress.update(&build_status(MqttCupsJobState::Processing, Some(impressions_completed)), now, THRESHOLD));
+            assert_eq!(progress.since, now);
+        }
+
+        // The wait restarts at the last progress.
+        let status = build_status(MqttCupsJobState::Processing, Some(3));
+        assert!(!progress.update(&status, start + TimeDelta::minutes(16), THRESHOLD));
+        assert!(progress.update(&status, start + TimeDelta::minutes(17), THRESHOLD));
+    }
+
+    #[test]
+    fn state_change_restarts_wait() {
+        let start = Utc::now();
+        let mut progress = JobProgress::new(&build_status(MqttCupsJobState::PendingHeld, None), start);
+
+        let now = start + TimeDelta::minutes(10);
+        assert!(!progress.update(&build_status(MqttCupsJobState::Processing, None), now, THRESHOLD));
+        assert_eq!(progress.since, now);
+    }
+
+    #[test]
+    fn uses_queue_threshold_over_default() {
+        let stuck_jobs = StuckJobs {
+            threshold: Some(HumanDuration(THRESHOLD)),
+            queues: [("DNP_Left".to_owned(), HumanDuration(Duration::from_secs(60)))].into(),
+        };
+
+        assert_eq!(stuck_jobs.threshold_for_queue("dnp_left"), Some(HumanDuration(Duration::from_secs(60))));
+        assert_eq!(stuck_jobs.threshold_for_queue("dnp_right"), Some(HumanDuration(THRESHOLD)));
+        assert_eq!(StuckJobs::default().threshold_for_queue("dnp_left"), None);
+    }
+}
@@ -0,0 +1,71 @@
+use std::sync::OnceLock;
+
+use minijinja::{context, value::Serde, Environment, UndefinedBehavior, Value};
+use snafu::ResultExt;
+
+use crate::{
+    cups_client::{client::CupsError, models::IppPrintQueueState},
+    get_settings,
+    mqtt_client::models::{MqttCupsPrintQueueStatus, MqttCupsServerStatus},
+    topics,
+    ApplicationError,
+};
+
+fn get_environment() -> &'static Environment<'static> {
+    static ENVIRONMENT: OnceLock<Environment<'static>> = OnceLock::new();
+    ENVIRONMENT.get_or_init(|| {
+        let mut environment = Environment::new();
+        // Report typos in variable names instead of silently rendering nothing.
+        environment.set_undefined_behavior(UndefinedBehavior::Strict);
+        environment
+    })
+}
+
+// ///////// //
+// Rendering //
+// ///////// //
+
+/// Renders the server status template, if configured. Next to the fields of the default payload, `server` (the CUPS
+/// host) and `queues` (the names of all print queues) can be used.
+pub fn render_server_status(status: &MqttCupsServerStatus, print_queues_result: &Result<Vec<IppPrintQueueState>, CupsError>) -> Result<Option<String>, ApplicationError> {
+    let Some(template) = get_template(&get_settings().mqtt.templates.server_status) else {
+        return Ok(None);
+    };
+
+    let queues = match print_queues_result {
+        Ok(print_queues) => print_queues.iter().map(|q| q.queue_name.clone()).collect(),
+        Err(_) => Vec::new(),
+    };
+    render(template, "server_status", context! {
+        server => topics::get_server_name(),
+        queues,
+        ..Value::from(Serde(status))
+    }).map(Some)
+}
+
+/// Renders the queue status template, if configured. Next to the fields of the default payload, `server` (the CUPS host),
+/// `location`, `device_uri`, `is_accepting_jobs`, `is_shared` and `defaults` (the job template defaults) can be used.
+pub fn render_queue_status(queue: &IppPrintQueueState, status: &MqttCupsPrintQueueStatus) -> Result<Option<String>, ApplicationError> {
+    let Some(template) = get_template(&get_settings().mqtt.templates.queue_status) else {
+        return Ok(None);
+    };
+
+    render(template, "queue_status", context! {
+        server => topics::get_server_name(),
+        location => queue.location.clone(),
+        device_uri => queue.device_uri.clone(),
+        is_accepting_jobs => queue.is_accepting_jobs,
+        is_shared => queue.is_shared,
+        defaults => queue.defaults.clone(),
+        ..Value::from(Serde(status))
+    }).map(Some)
+}
+
+/// An empty template (e.g. an empty ENV var) counts as not configured.
+fn get_template(template: &Option<String>) -> Option<&str> {
+    template.as_deref().filter(|template| !template.is_empty())
+}
+
+fn render(template: &str, template_name: &str, context: Value) -> Result<String, ApplicationError> {
+    get_environment().render_str(template, context).with_whatever_context(|e| format!("Could not render the {template_name} template: {e}"))
+}
@@ -0,0 +1,52 @@
+use std::sync::OnceLock;
+
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+
+use crate::{is_status_republished, mqtt_client::models::MqttTimestamps};
+
+/// A status as it was after its last change.
+struct StatusChange {
+    content: String,
+    state: String,
+    last_updated: DateTime<Utc>,
+    last_changed: DateTime<Utc>,
+}
+
+/// The last change of every status, keyed by topic.
+fn get_status_changes() -> &'static DashMap<String, StatusChange> {
+    static STATUS_CHANGES: OnceLock<DashMap<String, StatusChange>> = OnceLock::new();
+    STATUS_CHANGES.get_or_init(DashMap::new)
+}
+
+/// Builds the timestamps of the status for `topic`, in which `content` is the status without timestamps and `state` the
+/// part of it which counts for `last_changed`.
+pub fn build_timestamps(topic: &str, content: &str, state: &str, polled_at: DateTime<Utc>) -> MqttTimestamps {
+    let mut change = get_status_changes().entry(topic.to_owned()).or_insert_with(|| StatusChange {
+        content: content.to_owned(),
+        state: state.to_owned(),
+        last_updated: polled_at,
+        last_changed: polled_at,
+    });
+
+    if change.content != content {
+        change.content = content.to_owned();
+        change.last_updated = polled_at;
+    }
+    if change.state != state {
+        change.state = state.to_owned();
+        change.last_changed = polled_at;
+    }
+
+    MqttTimestamps {
+        last_updated: change.last_updated.to_rfc3339(),
+        last_changed: change.last_changed.to_rfc3339(),
+        // Only when the status is published on every poll anyway, as it would otherwise defeat leaving out unchanged ones.
+        polled_at: is_status_republished().then(|| polled_at.to_rfc3339()),
+    }
+}
+
+/// Forgets the changes of a status which isn't published anymore, like that of a removed queue.
+pub fn forget(topic: &str) {
+    get_status_changes().remove(topic);
+}
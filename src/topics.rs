@@ -0,0 +1,279 @@
+use std::{collections::BTreeSet, sync::{Mutex, OnceLock}, time::Duration};
+
+use log::{error, info};
+use url::Url;
+
+use crate::{config::models::PublishPolicy, cups_client::models::IppPrintQueueState, get_settings, homie};
+
+/// What a message is for, which decides its QoS and retain flag, and whether it's deduplicated.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MessageClass {
+    /// State of the print server, its queues and jobs, only published when changed.
+    Status,
+    /// Home Assistant discovery messages, only published when changed.
+    Discovery,
+    /// Things that happen once, like a job finishing, always published.
+    Event,
+    /// Answers to commands received through MQTT, only published when changed.
+    CommandResponse,
+}
+
+impl MessageClass {
+    pub fn policy(&self) -> &'static PublishPolicy {
+        let policies = &get_settings().mqtt.publish;
+        match self {
+            MessageClass::Status => &policies.status,
+            MessageClass::Discovery => &policies.discovery,
+            MessageClass::Event => &policies.events,
+            MessageClass::CommandResponse => &policies.command_responses,
+        }
+    }
+
+    pub fn is_deduplicated(&self) -> bool {
+        *self != MessageClass::Event
+    }
+}
+
+// ////////////// //
+// Topic building //
+// ////////////// //
+
+/// Fills in the placeholders of a topic template from `mqtt.topics`, with the topic name of the queue for `{queue}`.
+/// Placeholders without a value are left as they are.
+pub fn build_topic(template: &str, queue_name: Option<&str>, job_id: Option<i32>) -> String {
+    let queue_topic_name = queue_name.map(get_queue_topic_name);
+    fill_topic(template, queue_topic_name.as_deref(), job_id)
+}
+
+fn fill_topic(template: &str, queue_topic_name: Option<&str>, job_id: Option<i32>) -> String {
+    let mut topic = template
+        .replace("{root_topic}", &get_settings().mqtt.root_topic)
+        .replace("{server}", get_server_name());
+    if let Some(queue_topic_name) = queue_topic_name {
+        topic = topic.replace("{queue}", queue_topic_name);
+    }
+    if let Some(job_id) = job_id {
+        topic = topic.replace("{job_id}", &job_id.to_string());
+    }
+    topic
+}
+
+/// Builds the topic filter to subscribe to for a command topic template, with a wildcard for the queue.
+pub fn build_subscription(template: &str) -> String {
+    fill_topic(template, Some("+"), None)
+}
+
+/// Gets the queue name from a `topic` built from `template`, if it matches.
+pub fn parse_queue_name(template: &str, topic: &str) -> Option<String> {
+    let (prefix, suffix) = build_topic(template, None, None).split_once("{queue}").map(|(p, s)| (p.to_owned(), s.to_owned()))?;
+    topic.strip_prefix(&prefix)
+        .and_then(|t| t.strip_suffix(&suffix))
+        .filter(|queue_topic_name| !queue_topic_name.is_empty() && !queue_topic_name.contains('/'))
+        .map(parse_queue_topic_name)
+}
+
+/// All retained topics which are published for a queue, so they can be cleared when the queue is removed.
+pub fn build_queue_topics(queue_name: &str) -> Vec<String> {
+    let topics = &get_settings().mqtt.topics;
+    [&topics.queue_status, &topics.stuck_jobs, &topics.failover].iter()
+        .map(|template| build_topic(template, Some(queue_name), None))
+        .collect()
+}
+
+/// Home Assistant discovery topic of an entity of a queue, e.g. `homeassistant/sensor/cups2mqtt_office/state/config`.
+pub fn build_ha_queue_discovery_topic(component: &str, queue_name: &str, object_id: &str) -> String {
+    let ha = &get_settings().mqtt.ha;
+    format!("{}/{component}/{}_{}/{object_id}/config", ha.discovery_topic_prefix, ha.component_id, get_queue_slug(queue_name))
+}
+
+// /////////// //
+// Queue names //
+// /////////// //
+
+/// Name of a queue in topics: the one configured in `mqtt.queue_topic_names`, or the queue name. Both are escaped, so
+/// they stay a single topic level which can be subscribed to.
+pub fn get_queue_topic_name(queue_name: &str) -> String {
+    escape_topic_level(get_settings().mqtt.topic_name_for_queue(queue_name).unwrap_or(queue_name))
+}
+
+/// Gets the queue name back from its name in topics.
+fn parse_queue_topic_name(queue_topic_name: &str) -> String {
+    get_settings().mqtt.queue_topic_names.iter()
+        .find(|(_, topic_name)| escape_topic_level(topic_name) == queue_topic_name)
+        .map(|(queue_name, _)| queue_name.clone())
+        .unwrap_or_else(|| unescape_topic_level(queue_topic_name))
+}
+
+/// ID of a queue for Home Assistant, which only allows letters, digits, `_` and `-` in object IDs. Based on the name in
+/// `mqtt.queue_topic_names` if configured, so it can be changed when two queues end up with the same ID.
+pub fn get_queue_slug(queue_name: &str) -> String {
+    get_settings().mqtt.topic_name_for_queue(queue_name).unwrap_or(queue_name)
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '-' { c } else { '_' })
+        .collect()
+}
+
+/// Escapes what MQTT gives a meaning in a topic level like in URLs, e.g. `a/b` becomes `a%2Fb`: the level separator
+/// `/`, the wildcards `+` and `#`, NUL, and `$` at the start, which brokers reserve for their own topics. `%` is escaped
+/// too, so it can be unescaped again.
+pub fn escape_topic_level(name: &str) -> String {
+    let mut escaped = String::with_capacity(name.len());
+    for (i, c) in name.chars().enumerate() {
+        match c {
+            '%' | '/' | '+' | '#' | '\0' => escaped.push_str(&format!("%{:02X}", c as u32)),
+            '$' if i == 0 => escaped.push_str("%24"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Reverses [`escape_topic_level`], anything it doesn't escape is left as it is.
+fn unescape_topic_level(level: &str) -> String {
+    let mut name = String::with_capacity(level.len());
+    let mut rest = level;
+    while let Some(i) = rest.find('%') {
+        name.push_str(&rest[..i]);
+        let escaped = rest.get(i + 1..i + 3)
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            .filter(|c| b"%/+#\0$".contains(c));
+        match escaped {
+            Some(c) => {
+                name.push(c as char);
+                rest = &rest[i + 3..];
+            },
+            None => {
+                name.push('%');
+                rest = &rest[i + 1..];
+            },
+        }
+    }
+    name.push_str(rest);
+    name
+}
+
+/// Whether two different queues end up with the same topic name, Home Assistant ID or Homie node ID.
+pub fn is_name_shared(queue_name: &str, other_queue_name: &str) -> bool {
+    let settings = get_settings();
+    queue_name != other_queue_name
+        && (get_queue_topic_name(queue_name) == get_queue_topic_name(other_queue_name)
+            || (settings.mqtt.ha.enable_discovery && get_queue_slug(queue_name) == get_queue_slug(other_queue_name))
+            || (settings.mqtt.homie.enabled && homie::build_node_id(queue_name) == homie::build_node_id(other_queue_name)))
+}
+
+/// Queues which aren't published, as their topic name, Home Assistant ID or Homie node ID is already taken by another queue.
+fn get_colliding_queues() -> &'static Mutex<BTreeSet<String>> {
+    static COLLIDING_QUEUES: OnceLock<Mutex<BTreeSet<String>>> = OnceLock::new();
+    COLLIDING_QUEUES.get_or_init(|| Mutex::new(BTreeSet::new()))
+}
+
+/// Finds the queues sharing a topic name, Home Assistant ID or Homie node ID with another queue. Of those, the queue coming first in
+/// alphabetical order keeps it, the others aren't published, so they don't overwrite its messages.
+pub fn update_colliding_queues(print_queues: &[IppPrintQueueState]) {
+    let queue_names = print_queues.iter().map(|q| q.queue_name.as_str()).collect::<BTreeSet<_>>();
+    let mut colliding_queues = get_colliding_queues().lock().unwrap();
+
+    let mut new_colliding_queues = BTreeSet::new();
+    for (i, queue_name) in queue_names.iter().enumerate() {
+        let Some(other_queue_name) = queue_names.iter().take(i).find(|other_queue_name| is_name_shared(queue_name, other_queue_name)) else {
+            continue;
+        };
+        if !colliding_queues.contains(*queue_name) {
+            error!("Queue [{queue_name}] has the same topic name, Home Assistant ID or Homie node ID as queue [{other_queue_name}], so it isn't published. Set another name for either in mqtt.queue_topic_names.");
+        }
+        new_colliding_queues.insert(queue_name.to_string());
+    }
+    for queue_name in colliding_queues.difference(&new_colliding_queues) {
+        info!("Queue [{queue_name}] no longer shares its topic name, Home Assistant ID or Homie node ID, publishing it again");
+    }
+    *colliding_queues = new_colliding_queues;
+}
+
+/// Whether a queue isn't published, as its topic name, Home Assistant ID or Homie node ID is taken by another queue.
+pub fn is_colliding(queue_name: &str) -> bool {
+    get_colliding_queues().lock().unwrap().contains(queue_name)
+}
+
+/// How long an unchanged message may go without being published again, if there's a limit. The shortest max age of the
+/// topic filters in `mqtt.max_age` matching the topic applies, otherwise that of the message class.
+pub fn get_max_age(class: MessageClass, topic: &str) -> Option<Duration> {
+    get_settings().mqtt.max_age.iter()
+        .filter(|(filter, _)| matches_filter(&build_topic(filter, None, None), topic))
+        .map(|(_, max_age)| max_age.0)
+        .min()
+        .or(class.policy().max_age.as_ref().map(|max_age| max_age.0))
+}
+
+/// Whether `topic` matches the MQTT topic filter `filter`, which may contain the `+` and `#` wildcards.
+pub fn matches_filter(filter: &str, topic: &str) -> bool {
+    let mut topic_levels = topic.split('/');
+    for filter_level in filter.split('/') {
+        match (filter_level, topic_levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {},
+            (filter_level, Some(topic_level)) if filter_level == topic_level => {},
+            _ => return false,
+        }
+    }
+    topic_levels.next().is_none()
+}
+
+/// Host of the CUPS server, used for `{server}` in topics.
+pub fn get_server_name() -> &'static str {
+    static SERVER_NAME: OnceLock<String> = OnceLock::new();
+    SERVER_NAME.get_or_init(|| {
+        Url::parse(&get_settings().cups.uri).ok().and_then(|url| url.host_str().map(str::to_owned)).unwrap_or_default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_special_characters() {
+        assert_eq!(escape_topic_level("booth/2"), "booth%2F2");
+        assert_eq!(escape_topic_level("a+b#c"), "a%2Bb%23c");
+        assert_eq!(escape_topic_level("100%"), "100%25");
+        assert_eq!(escape_topic_level("$SYS"), "%24SYS");
+        assert_eq!(escape_topic_level("a$b"), "a$b");
+        assert_eq!(escape_topic_level("a\0b"), "a%00b");
+    }
+
+    #[test]
+    fn unescapes_what_was_escaped() {
+        for name in ["office", "booth/2", "a+b#c", "100%", "%2F", "$SYS", "a$b", "a\0b", "%%%", "Büro 1"] {
+            assert_eq!(unescape_topic_level(&escape_topic_level(name)), name);
+        }
+    }
+
+    #[test]
+    fn leaves_unknown_escapes_alone() {
+        assert_eq!(unescape_topic_level("100%"), "100%");
+        assert_eq!(unescape_topic_level("%41"), "%41");
+        assert_eq!(unescape_topic_level("%zz"), "%zz");
+    }
+
+    #[test]
+    fn matches_single_level_wildcard() {
+        assert!(matches_filter("cups2mqtt/+/state", "cups2mqtt/office/state"));
+        assert!(!matches_filter("cups2mqtt/+/state", "cups2mqtt/office/markers/state"));
+        assert!(!matches_filter("cups2mqtt/+", "cups2mqtt"));
+        assert!(matches_filter("+/+", "cups2mqtt/office"));
+    }
+
+    #[test]
+    fn matches_multi_level_wildcard() {
+        assert!(matches_filter("cups2mqtt/#", "cups2mqtt/office/markers/Black/level"));
+        assert!(matches_filter("cups2mqtt/#", "cups2mqtt"));
+        assert!(matches_filter("#", "cups2mqtt/office"));
+        assert!(!matches_filter("cups2mqtt/#", "homeassistant/sensor"));
+    }
+
+    #[test]
+    fn matches_exact_topic() {
+        assert!(matches_filter("cups2mqtt/office", "cups2mqtt/office"));
+        assert!(!matches_filter("cups2mqtt/office", "cups2mqtt/office/state"));
+        assert!(!matches_filter("cups2mqtt/office/state", "cups2mqtt/office"));
+    }
+}